@@ -0,0 +1,128 @@
+//! Puts `memory.x` in the linker search path, so that `cortex-m-rt` finds it
+//! wherever the firmware is built from, and describes the build for the `V` and `v`
+//! queries in `build_info.rs`.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Board the pin assignments in `main.rs` are for.
+const BOARD: &str = "nucleo-f446ze";
+/// Revision of the board, as major and minor nibbles.
+const BOARD_REVISION: u8 = 0x10;
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out.join("memory.x"), include_bytes!("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+
+    write_build_info(&out.join("build_info.rs"));
+}
+
+fn write_build_info(path: &Path) {
+    let major: u8 = env::var("CARGO_PKG_VERSION_MAJOR")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let minor: u8 = env::var("CARGO_PKG_VERSION_MINOR")
+        .unwrap()
+        .parse()
+        .unwrap();
+    // Lawicel versions are a digit each for major and minor
+    let software_version = (major.min(0xF) << 4) | minor.min(0xF);
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            let feature = name.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    let features = if features.is_empty() {
+        "none".to_string()
+    } else {
+        features.join(",")
+    };
+
+    fs::write(
+        path,
+        format!(
+            "BuildInfo {{\n    hardware_version: {BOARD_REVISION:#04x},\n    \
+             software_version: {software_version:#04x},\n    version: {:?},\n    \
+             board: {BOARD:?},\n    git_hash: {:?},\n    build_date: {:?},\n    \
+             features: {features:?},\n}}\n",
+            env::var("CARGO_PKG_VERSION").unwrap(),
+            git_hash(),
+            build_date(),
+        ),
+    )
+    .unwrap();
+}
+
+/// Returns the abbreviated hash of the checked out commit, marked `-dirty` if tracked
+/// files have been changed since.
+fn git_hash() -> String {
+    let Some(hash) = git(&["rev-parse", "--short=12", "HEAD"]) else {
+        return "unknown".to_string();
+    };
+    // rebuild on commits, checkouts and changes to the sources, which may make it dirty
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=../src");
+    for path in ["HEAD", "index"] {
+        if let Some(path) = git(&["rev-parse", "--git-path", path]) {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    if let Some(head) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        if let Some(path) = git(&["rev-parse", "--git-path", &head]) {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+
+    match git(&["status", "--porcelain", "--untracked-files=no"]) {
+        Some(changes) if changes.is_empty() => hash,
+        _ => hash + "-dirty",
+    }
+}
+
+/// Runs git, returning its trimmed output if it succeeds.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+/// Returns the UTC date of the build as `YYYY-MM-DD`, taken from `SOURCE_DATE_EPOCH` if set
+/// so that builds can be reproduced.
+fn build_date() -> String {
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let seconds = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse().expect("SOURCE_DATE_EPOCH must be in seconds"),
+        Err(_e) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    };
+
+    // civil date from days since 1970-01-01, per Howard Hinnant's `civil_from_days`
+    let days = (seconds / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
@@ -0,0 +1,10 @@
+MEMORY
+{
+  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 384K
+  /* last sector, holding the settings saved with the Q command */
+  CONFIG (r) : ORIGIN = 0x08060000, LENGTH = 128K
+  RAM (xrw)  : ORIGIN = 0x20000000, LENGTH = 128K
+}
+
+_config_start = ORIGIN(CONFIG);
+_config_end = ORIGIN(CONFIG) + LENGTH(CONFIG);
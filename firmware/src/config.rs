@@ -0,0 +1,77 @@
+use core::ptr::addr_of;
+use rusty_can::config::{ConfigError, ConfigFlash};
+use stm32f4xx_hal::{
+    flash::{FlashExt, LockedFlash},
+    pac,
+};
+
+extern "C" {
+    // bounds of the CONFIG region in memory.x
+    static _config_start: u8;
+    static _config_end: u8;
+}
+
+/// Flash sector reserved for the settings in memory.x.
+pub struct ConfigSector {
+    flash: LockedFlash,
+    /// Offset of the sector from the start of flash.
+    offset: usize,
+    len: usize,
+    sector: u8,
+}
+
+impl ConfigSector {
+    pub fn new(flash: pac::FLASH) -> Self {
+        let flash = LockedFlash::new(flash);
+        // only the addresses of the linker symbols are meaningful
+        let start = addr_of!(_config_start) as usize;
+        let end = addr_of!(_config_end) as usize;
+        let offset = start - flash.address();
+        let sector = flash.sector(offset).unwrap();
+        // erasing clears a whole sector, so the region must be exactly one
+        assert!(sector.offset == offset && sector.size == end - start);
+
+        ConfigSector {
+            flash,
+            offset,
+            len: end - start,
+            sector: sector.number,
+        }
+    }
+
+    /// Discards data cached from the sector before it was changed.
+    fn reset_data_cache(&mut self) {
+        // safety: `flash` is owned, and the cache must be disabled while it is reset
+        let acr = unsafe { &(*pac::FLASH::ptr()).acr };
+        acr.modify(|_, w| w.dcen().clear_bit());
+        acr.modify(|_, w| w.dcrst().set_bit());
+        acr.modify(|_, w| w.dcrst().clear_bit().dcen().set_bit());
+    }
+}
+
+impl ConfigFlash for ConfigSector {
+    fn capacity(&self) -> usize {
+        self.len
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        let start = self.offset + offset;
+        data.copy_from_slice(&self.flash.read()[start..start + data.len()]);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), ConfigError> {
+        let result = self
+            .flash
+            .unlocked()
+            .program(self.offset + offset, data.iter());
+        self.reset_data_cache();
+        result.map_err(|_e| ConfigError::Flash)
+    }
+
+    /// Erases the sector, stalling everything running from flash for up to 4 s.
+    fn erase(&mut self) -> Result<(), ConfigError> {
+        let result = self.flash.unlocked().erase(self.sector);
+        self.reset_data_cache();
+        result.map_err(|_e| ConfigError::Flash)
+    }
+}
@@ -0,0 +1,44 @@
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::{addr_of, addr_of_mut};
+use cortex_m::peripheral::SCB;
+use rusty_can::reset::{PanicRecord, ResetCause, ResetInfo};
+use stm32f4xx_hal::pac;
+
+/// Left untouched by the startup code, so that it survives the reset after a panic.
+#[link_section = ".uninit.PANIC_RECORD"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+/// Records the panic location, lights the red LED and resets.
+#[inline(never)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    let record = match info.location() {
+        Some(location) => PanicRecord::new(location.file(), location.line()),
+        None => PanicRecord::new("", 0),
+    };
+    // safety: interrupts are disabled and nothing else accesses the record after init
+    unsafe { addr_of_mut!(PANIC_RECORD).write_volatile(MaybeUninit::new(record)) };
+
+    // safety: only sets the red LED (PB14) output, the port is clocked once init has run
+    unsafe { (*pac::GPIOB::ptr()).bsrr.write(|w| w.bs14().set_bit()) };
+
+    SCB::sys_reset()
+}
+
+/// Reads why the adapter started, and where it panicked if that was the reason.
+/// Clears the reset flags and the panic record, so must only be called once, from init.
+pub fn take_reset_info(rcc: &pac::RCC) -> ResetInfo {
+    let cause = ResetCause::from_csr(rcc.csr.read().bits());
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+
+    // safety: any contents are a valid `PanicRecord`, and interrupts are not yet enabled
+    let record = unsafe { addr_of!(PANIC_RECORD).read_volatile().assume_init() };
+    unsafe { addr_of_mut!(PANIC_RECORD).write_volatile(MaybeUninit::new(PanicRecord::empty())) };
+    let location = record.location();
+
+    // a panic is reported as a software reset, only keep its record for that cause
+    ResetInfo::new(cause, location.filter(|_| cause == ResetCause::Software))
+}
@@ -0,0 +1,21 @@
+use rusty_can::identity::{serial_number_from_uid, BuildInfo, SerialNumber};
+use stm32f4xx_hal::signature::Uid;
+
+/// Description of this build, generated by `build.rs`.
+pub const BUILD_INFO: BuildInfo = include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+/// Returns the serial number derived from the unique device ID.
+pub fn device_serial_number() -> SerialNumber {
+    serial_number_from_uid(&device_uid())
+}
+
+/// Reads the 96-bit unique device ID, in the order it is stored.
+fn device_uid() -> [u8; 12] {
+    let uid = Uid::get();
+    let mut bytes = [0u8; 12];
+    bytes[0..2].copy_from_slice(&uid.x().to_le_bytes());
+    bytes[2..4].copy_from_slice(&uid.y().to_le_bytes());
+    bytes[4] = uid.waf_num();
+    bytes[5..12].copy_from_slice(uid.lot_num().as_bytes());
+    bytes
+}
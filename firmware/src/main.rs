@@ -0,0 +1,374 @@
+#![no_main]
+#![no_std]
+
+mod config;
+mod fault;
+mod identity;
+#[cfg(not(feature = "usb"))]
+mod uart;
+#[cfg(feature = "usb")]
+mod usb;
+
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use crate::config::ConfigSector;
+    #[cfg(not(feature = "usb"))]
+    use crate::uart::UartTransport as HostTransport;
+    #[cfg(feature = "usb")]
+    use crate::usb::UsbTransport as HostTransport;
+    use bxcan::Interrupts;
+    use rusty_can::canbus::{CANBus, CanDriver};
+    use rusty_can::config::ConfigStore;
+    use rusty_can::slcan::{QueueType, TxQueueType, SLCAN};
+    use rusty_can::transport::Transport;
+    use rusty_can::watchdog::TaskMonitor;
+    #[cfg(feature = "usb")]
+    use stm32f4xx_hal::otg_fs::USB;
+    use stm32f4xx_hal::{
+        can::Can,
+        gpio::{Output, AF9, PB0, PB14, PB7, PD0, PD1},
+        pac,
+        prelude::*,
+        rcc::RccExt,
+        timer::monotonic::MonoTimerUs,
+        watchdog::IndependentWatchdog,
+    };
+    #[cfg(not(feature = "usb"))]
+    use stm32f4xx_hal::{
+        gpio::{AF7, PD8, PD9},
+        serial::Config,
+    };
+
+    type CanType = CANBus<Can<pac::CAN1, (PD1<AF9>, PD0<AF9>)>>;
+
+    #[shared]
+    struct Shared {
+        // all users run at the same priority, so queued frames never interleave
+        #[lock_free]
+        tx_queue: TxQueueType,
+        #[lock_free]
+        rx_queue: QueueType,
+        #[lock_free]
+        can: CanType,
+        #[lock_free]
+        slcan: SLCAN,
+        #[lock_free]
+        host: HostTransport,
+        #[lock_free]
+        led_blue: PB7<Output>,
+        #[lock_free]
+        led_red: PB14<Output>,
+        #[lock_free]
+        monitor: TaskMonitor,
+        #[lock_free]
+        config: ConfigStore<ConfigSector>,
+    }
+
+    #[local]
+    struct Local {
+        led_green: PB0<Output>,
+        iwdg: IndependentWatchdog,
+    }
+
+    #[monotonic(binds = TIM2, default = true)]
+    type MicrosecMono = MonoTimerUs<pac::TIM2>;
+
+    /// Interrupt of the task that exchanges bytes with the host.
+    #[cfg(not(feature = "usb"))]
+    const HOST_INTERRUPT: pac::Interrupt = pac::Interrupt::USART3;
+    #[cfg(feature = "usb")]
+    const HOST_INTERRUPT: pac::Interrupt = pac::Interrupt::OTG_FS;
+
+    /// Periodic tasks which must keep checking in for the watchdog to be fed.
+    /// Every task shares one priority, so a stall in any task also stops these.
+    const TICK_TASK: u32 = 0;
+    const BLINK_TASK: u32 = 1;
+    const SUPERVISED_TASKS: u32 = 2;
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        #[cfg(feature = "usb")]
+        let gpioa = ctx.device.GPIOA.split();
+        let gpiob = ctx.device.GPIOB.split();
+        let gpiod = ctx.device.GPIOD.split();
+
+        let reset_info = crate::fault::take_reset_info(&ctx.device.RCC);
+        let rcc = ctx.device.RCC.constrain();
+        // APB1 clocks the CAN peripheral; 36 MHz divides evenly into every standard bit rate.
+        // The PLL also provides the 48 MHz USB clock.
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.MHz())
+            .sysclk(144.MHz())
+            .pclk1(36.MHz())
+            .require_pll48clk()
+            .freeze();
+
+        let led_green = gpiob.pb0.into_push_pull_output();
+        let led_blue = gpiob.pb7.into_push_pull_output();
+        let mut led_red = gpiob.pb14.into_push_pull_output();
+        if reset_info.panic.is_some() {
+            led_red.set_high();
+        }
+
+        let mono = ctx.device.TIM2.monotonic_us(&clocks);
+        tick::spawn().ok();
+        tick_blink::spawn().ok();
+
+        // resets the adapter unless every supervised task has run within the period.
+        // Saving settings may erase the 128 KB config sector, which stalls every task for
+        // up to 4 s as the HAL erases with PSIZE x8, so the period allows for that.
+        let mut iwdg = IndependentWatchdog::new(ctx.device.IWDG);
+        iwdg.stop_on_debug(&ctx.device.DBGMCU, true);
+        iwdg.start(6000.millis());
+        watchdog::spawn().ok();
+
+        let mut can = {
+            let rx_pin: PD0<AF9> = gpiod.pd0.into_alternate();
+            let tx_pin: PD1<AF9> = gpiod.pd1.into_alternate();
+
+            let can = ctx.device.CAN1.can((tx_pin, rx_pin));
+            let mut can = CANBus::new(can, clocks.pclk1().raw());
+            can.enable_interrupts(
+                Interrupts::TRANSMIT_MAILBOX_EMPTY
+                    | Interrupts::FIFO0_MESSAGE_PENDING
+                    | Interrupts::FIFO0_OVERRUN
+                    | Interrupts::FIFO1_MESSAGE_PENDING
+                    | Interrupts::FIFO1_OVERRUN,
+            );
+            can.enable_bus_off_interrupt();
+            can
+        };
+
+        let mut slcan = SLCAN::new();
+        slcan.set_reset_info(reset_info);
+        slcan.set_build_info(crate::identity::BUILD_INFO);
+        slcan.set_serial_number(crate::identity::device_serial_number());
+
+        let config = ConfigStore::new(ConfigSector::new(ctx.device.FLASH));
+        if let Some(settings) = config.load() {
+            // may open the channel, for logging without a host
+            if slcan.apply_settings(&settings, &mut can).is_err() {
+                led_red.set_high();
+            }
+        }
+
+        #[cfg(not(feature = "usb"))]
+        let host = {
+            let tx_pin: PD8<AF7> = gpiod.pd8.into_alternate();
+            let rx_pin: PD9<AF7> = gpiod.pd9.into_alternate();
+
+            let serial = ctx
+                .device
+                .USART3
+                .serial(
+                    (tx_pin, rx_pin),
+                    Config::default().baudrate(crate::uart::saved_baud_rate().bps()),
+                    &clocks,
+                )
+                .unwrap();
+
+            let (tx, rx) = serial.split();
+            HostTransport::new(rx, tx, clocks.pclk1().raw())
+        };
+
+        #[cfg(feature = "usb")]
+        let host = HostTransport::new(
+            USB {
+                usb_global: ctx.device.OTG_FS_GLOBAL,
+                usb_device: ctx.device.OTG_FS_DEVICE,
+                usb_pwrclk: ctx.device.OTG_FS_PWRCLK,
+                pin_dm: gpioa.pa11.into_alternate(),
+                pin_dp: gpioa.pa12.into_alternate(),
+                hclk: clocks.hclk(),
+            },
+            // a serial number saved later takes effect at the next reset
+            slcan.serial_number(),
+        );
+
+        let tx_queue = TxQueueType::new();
+        let rx_queue = QueueType::new();
+
+        (
+            Shared {
+                tx_queue,
+                rx_queue,
+                can,
+                slcan,
+                host,
+                led_blue,
+                led_red,
+                monitor: TaskMonitor::new(SUPERVISED_TASKS),
+                config,
+            },
+            Local { led_green, iwdg },
+            init::Monotonics(mono),
+        )
+    }
+
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            // everything runs from interrupts
+            cortex_m::asm::wfi();
+        }
+    }
+
+    #[task(priority=2, local=[led_green], shared=[monitor])]
+    fn tick_blink(ctx: tick_blink::Context) {
+        ctx.local.led_green.toggle();
+        ctx.shared.monitor.check_in(BLINK_TASK);
+        tick_blink::spawn_after(250.millis()).ok();
+    }
+
+    #[task(priority=2, shared=[slcan, can, tx_queue, monitor])]
+    fn tick(ctx: tick::Context) {
+        // keep the frame timestamp running across timer wraps
+        let now_us = monotonics::now().ticks();
+        ctx.shared.slcan.update_timestamp(now_us);
+        ctx.shared
+            .slcan
+            .update_bus_state(ctx.shared.can, now_us, ctx.shared.tx_queue);
+        start_transmit(ctx.shared.tx_queue);
+        ctx.shared.monitor.check_in(TICK_TASK);
+        tick::spawn_after(50.millis()).ok();
+    }
+
+    #[task(priority=2, local=[iwdg], shared=[monitor])]
+    fn watchdog(ctx: watchdog::Context) {
+        if ctx.shared.monitor.all_checked_in() {
+            ctx.local.iwdg.feed();
+        }
+        watchdog::spawn_after(500.millis()).ok();
+    }
+
+    #[task(priority=2, binds=CAN1_RX0, shared=[can, tx_queue, slcan])]
+    fn can_rx0(ctx: can_rx0::Context) {
+        receive_frames(ctx.shared.can, ctx.shared.slcan, ctx.shared.tx_queue);
+        start_transmit(ctx.shared.tx_queue);
+    }
+
+    #[task(priority=2, binds=CAN1_RX1, shared=[can, tx_queue, slcan])]
+    fn can_rx1(ctx: can_rx1::Context) {
+        receive_frames(ctx.shared.can, ctx.shared.slcan, ctx.shared.tx_queue);
+        start_transmit(ctx.shared.tx_queue);
+    }
+
+    #[task(priority=2, binds=CAN1_TX, shared=[can])]
+    fn can_tx(ctx: can_tx::Context) {
+        ctx.shared.can.service_transmit();
+    }
+
+    #[task(priority=2, binds=CAN1_SCE, shared=[can])]
+    fn can_sce(ctx: can_sce::Context) {
+        // automatic recovery may end bus-off long before the next tick
+        ctx.shared.can.service_status_change();
+    }
+
+    // Only the interrupt of the transport selected at build time is ever enabled.
+    #[task(priority=2, binds=USART3, shared=[host, tx_queue, rx_queue, can, slcan, config, led_red, led_blue])]
+    fn serial(ctx: serial::Context) {
+        service_host(
+            ctx.shared.host,
+            ctx.shared.slcan,
+            ctx.shared.can,
+            ctx.shared.rx_queue,
+            ctx.shared.tx_queue,
+            ctx.shared.led_red,
+            ctx.shared.led_blue,
+        );
+        save_settings(ctx.shared.slcan, ctx.shared.config, ctx.shared.led_red);
+    }
+
+    #[task(priority=2, binds=OTG_FS, shared=[host, tx_queue, rx_queue, can, slcan, config, led_red, led_blue])]
+    fn usb(ctx: usb::Context) {
+        service_host(
+            ctx.shared.host,
+            ctx.shared.slcan,
+            ctx.shared.can,
+            ctx.shared.rx_queue,
+            ctx.shared.tx_queue,
+            ctx.shared.led_red,
+            ctx.shared.led_blue,
+        );
+        save_settings(ctx.shared.slcan, ctx.shared.config, ctx.shared.led_red);
+    }
+
+    /// Runs the commands received from the host and sends as much of the tx queue as the
+    /// transport accepts, the rest is sent from the next transport interrupt.
+    fn service_host(
+        host: &mut HostTransport,
+        slcan: &mut SLCAN,
+        can: &mut CanType,
+        rx_queue: &mut QueueType,
+        tx_queue: &mut TxQueueType,
+        led_red: &mut PB14<Output>,
+        led_blue: &mut PB7<Output>,
+    ) {
+        host.poll();
+
+        while let Some(cmd) = slcan.receive_command(host, rx_queue) {
+            match cmd {
+                Ok(cmd) => {
+                    // Handle command
+                    let cmd_output = cmd.run(slcan, can);
+                    match &cmd_output {
+                        Ok(_) => {}
+                        Err(_e) => led_red.set_high(),
+                    }
+                    // a response which does not fit is reported in the status flags
+                    if slcan.handle_command_output(&cmd_output, tx_queue).is_err() {
+                        led_red.set_high();
+                    }
+                }
+                Err(_e) => {
+                    // Invalid command
+                    led_red.set_high()
+                }
+            }
+        }
+
+        if slcan.transmit_output(host, tx_queue) {
+            led_blue.set_high();
+        } else {
+            led_blue.set_low();
+        }
+    }
+
+    /// Writes the settings to flash if the host asked to change them.
+    fn save_settings(
+        slcan: &mut SLCAN,
+        config: &mut ConfigStore<ConfigSector>,
+        led_red: &mut PB14<Output>,
+    ) {
+        if let Some(update) = slcan.take_settings_to_save() {
+            if config.update(&update).is_err() {
+                led_red.set_high();
+            }
+        }
+    }
+
+    /// Drains both receive FIFOs, clearing the pending receive interrupts.
+    fn receive_frames(can: &mut CanType, slcan: &mut SLCAN, tx_queue: &mut TxQueueType) {
+        loop {
+            match can.receive() {
+                Ok(frame) => {
+                    let now_us = monotonics::now().ticks();
+                    slcan
+                        .handle_incoming_can_frame(&frame, now_us, tx_queue)
+                        .ok(); // dropped frames are reported in the status flags
+                }
+                // overruns are reported in the status flags, keep draining
+                Err(nb::Error::Other(_e)) => {}
+                Err(nb::Error::WouldBlock) => break,
+            }
+        }
+    }
+
+    /// Runs the transport task to start sending anything queued.
+    fn start_transmit(tx_queue: &TxQueueType) {
+        if !tx_queue.is_empty() {
+            rtic::pend(HOST_INTERRUPT);
+        }
+    }
+}
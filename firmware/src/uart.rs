@@ -0,0 +1,99 @@
+use core::mem::MaybeUninit;
+use core::ptr::{addr_of, addr_of_mut};
+use rusty_can::slcan::{DEFAULT_UART_BAUD_RATE, UART_BAUD_RATES};
+use rusty_can::transport::Transport;
+use stm32f4xx_hal::{
+    pac,
+    prelude::*,
+    serial::{Rx, Tx},
+};
+
+/// Baud rate selected by the host, left untouched by the startup code so that it survives
+/// a reset, e.g. by the watchdog, but not a power cycle. Held with its complement, so that
+/// whatever RAM contains after power-on is rejected.
+#[link_section = ".uninit.BAUD_RATE"]
+static mut BAUD_RATE: MaybeUninit<[u32; 2]> = MaybeUninit::uninit();
+
+/// Returns the baud rate last selected by the host, or the default after power-on.
+/// Must only be called from init.
+pub fn saved_baud_rate() -> u32 {
+    // safety: any contents are a valid array, and interrupts are not yet enabled
+    let [baud, check] = unsafe { addr_of!(BAUD_RATE).read_volatile().assume_init() };
+    if check == !baud && UART_BAUD_RATES.contains(&baud) {
+        baud
+    } else {
+        DEFAULT_UART_BAUD_RATE
+    }
+}
+
+/// Connection to the host over USART3 (the Nucleo ST-Link virtual COM port).
+/// Transmission runs from the TXE interrupt while bytes are pending.
+pub struct UartTransport {
+    rx: Rx<pac::USART3, u8>,
+    tx: Tx<pac::USART3, u8>,
+    /// Frequency of the clock driving the USART (APB1).
+    pclk_hz: u32,
+}
+
+impl UartTransport {
+    pub fn new(mut rx: Rx<pac::USART3, u8>, tx: Tx<pac::USART3, u8>, pclk_hz: u32) -> Self {
+        rx.listen();
+        UartTransport { rx, tx, pclk_hz }
+    }
+}
+
+/// Calculates the OVER8 bit and BRR value for `baud`, as done by the HAL when configuring
+/// the serial port. Oversamples by 16 where the clock allows it.
+fn usart_divider(pclk_hz: u32, baud: u32) -> Option<(bool, u32)> {
+    if pclk_hz / 16 >= baud {
+        Some((false, (pclk_hz + baud / 2) / baud))
+    } else if pclk_hz / 8 >= baud {
+        // only 3 fractional bits are used when oversampling by 8
+        let div = (pclk_hz * 2 + baud / 2) / baud;
+        Some((true, (div & !0xF) | ((div & 0xF) >> 1)))
+    } else {
+        None
+    }
+}
+
+impl Transport for UartTransport {
+    fn read(&mut self) -> Option<u8> {
+        self.rx.read().ok()
+    }
+
+    fn write(&mut self, data: &[u8]) -> usize {
+        let mut count = 0;
+        for &byte in data {
+            if self.tx.write(byte).is_err() {
+                break;
+            }
+            count += 1;
+        }
+        count
+    }
+
+    fn set_tx_pending(&mut self, pending: bool) {
+        if pending {
+            self.tx.listen();
+        } else {
+            self.tx.unlisten();
+        }
+    }
+
+    fn set_baud_rate(&mut self, baud: u32) {
+        let Some((over8, brr)) = usart_divider(self.pclk_hz, baud) else {
+            return;
+        };
+        // let the last byte of the response leave at the old rate
+        nb::block!(self.tx.flush()).ok();
+
+        // safety: the register block is owned by `rx` and `tx`, which only use it from this task
+        unsafe {
+            let usart = &*pac::USART3::ptr();
+            usart.cr1.modify(|_, w| w.ue().clear_bit());
+            usart.brr.write(|w| w.bits(brr));
+            usart.cr1.modify(|_, w| w.over8().bit(over8).ue().set_bit());
+            addr_of_mut!(BAUD_RATE).write_volatile(MaybeUninit::new([baud, !baud]));
+        }
+    }
+}
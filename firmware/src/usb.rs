@@ -0,0 +1,57 @@
+use rusty_can::identity::SerialNumber;
+use rusty_can::transport::Transport;
+use stm32f4xx_hal::otg_fs::{UsbBus, UsbBusType, USB};
+use usb_device::{bus::UsbBusAllocator, prelude::*};
+use usbd_serial::SerialPort;
+
+const USB_VID_PID: UsbVidPid = UsbVidPid(0x16c0, 0x27dd);
+
+/// Connection to the host as a USB CDC-ACM serial device on the OTG FS port.
+/// Transmission resumes from the OTG FS interrupt once the host has collected a packet.
+pub struct UsbTransport {
+    device: UsbDevice<'static, UsbBusType>,
+    serial: SerialPort<'static, UsbBusType>,
+}
+
+impl UsbTransport {
+    /// Creates the USB device, reporting the same serial number as `N`.
+    /// Must only be called once.
+    pub fn new(usb: USB, serial_number: SerialNumber) -> Self {
+        let ep_memory = cortex_m::singleton!(: [u32; 1024] = [0; 1024]).unwrap();
+        let usb_bus = UsbBus::new(usb, ep_memory);
+        let usb_bus = cortex_m::singleton!(: UsbBusAllocator<UsbBusType> = usb_bus).unwrap();
+
+        let serial_number = cortex_m::singleton!(: SerialNumber = serial_number).unwrap();
+        // serial numbers are made of digits and letters only
+        let serial_number = core::str::from_utf8(serial_number).unwrap();
+
+        let serial = SerialPort::new(usb_bus);
+        let device = UsbDeviceBuilder::new(usb_bus, USB_VID_PID)
+            .manufacturer("rusty-can")
+            .product("SLCAN adapter")
+            .serial_number(serial_number)
+            .device_class(usbd_serial::USB_CLASS_CDC)
+            .build();
+
+        UsbTransport { device, serial }
+    }
+}
+
+impl Transport for UsbTransport {
+    /// Services the USB device. Must be called from the OTG FS interrupt.
+    fn poll(&mut self) {
+        self.device.poll(&mut [&mut self.serial]);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let mut buf = [0u8; 1];
+        match self.serial.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> usize {
+        self.serial.write(data).unwrap_or(0)
+    }
+}
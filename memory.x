@@ -1,5 +0,0 @@
-MEMORY
-{
-  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 512K
-  RAM (xrw)  : ORIGIN = 0x20000000, LENGTH = 128K
-}
\ No newline at end of file
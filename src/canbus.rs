@@ -1,5 +1,40 @@
-use bxcan::{self, filter::Mask32, Frame, TransmitStatus};
-use can_bit_timings::can_timings_bxcan;
+pub mod mock;
+pub mod timing;
+
+use bxcan::{self, filter::Mask32, ExtendedId, Frame, Interrupts, StandardId};
+use core::convert::Infallible;
+
+/// Bits of the bxCAN BTR register that hold the bit timing.
+const BTR_TIMING_MASK: u32 = 0x037F_03FF;
+
+// Offsets of registers not exposed by bxcan, relative to the peripheral base
+const MCR_OFFSET: usize = 0x00;
+const MSR_OFFSET: usize = 0x04;
+const TSR_OFFSET: usize = 0x08;
+const RF0R_OFFSET: usize = 0x0C;
+const RF1R_OFFSET: usize = 0x10;
+const IER_OFFSET: usize = 0x14;
+const ESR_OFFSET: usize = 0x18;
+
+const MCR_ABOM: u32 = 1 << 6;
+const MSR_ERRI: u32 = 1 << 2;
+const IER_BOFIE: u32 = 1 << 10;
+const TSR_ALST: [u32; 3] = [1 << 2, 1 << 10, 1 << 18];
+const TSR_RQCP: [u32; 3] = [1 << 0, 1 << 8, 1 << 16];
+const TSR_TME: u32 = 0b111 << 26;
+const RFR_FOVR: u32 = 1 << 4;
+const ESR_EWGF: u32 = 1 << 0;
+const ESR_EPVF: u32 = 1 << 1;
+const ESR_BOFF: u32 = 1 << 2;
+const ESR_LEC_SHIFT: u32 = 4;
+const ESR_LEC_MASK: u32 = 0b111 << ESR_LEC_SHIFT;
+const ESR_TEC_SHIFT: u32 = 16;
+const ESR_REC_SHIFT: u32 = 24;
+/// Last error code value reserved for software, used to detect new errors.
+const LEC_UNSET: u32 = 0b111;
+
+/// Number of frames the software transmit queue holds behind the three hardware mailboxes.
+pub const TRANSMIT_QUEUE_LEN: usize = 32;
 
 #[derive(Debug)]
 pub enum CANError {
@@ -12,7 +47,88 @@ pub enum ErrorKind {
     BufferOverrun,
 }
 
-#[derive(Clone, Copy)]
+/// Error and status conditions reported by the CAN controller.
+/// Latched conditions are cleared in the controller when read.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BusStatus {
+    /// At least one error counter has reached the warning limit (96).
+    pub error_warning: bool,
+    /// At least one error counter has reached the error passive limit (128).
+    pub error_passive: bool,
+    /// The controller is in bus-off state.
+    pub bus_off: bool,
+    /// An error has been detected on the bus since the last read.
+    pub bus_error: bool,
+    /// A transmission lost arbitration since the last read.
+    pub arbitration_lost: bool,
+    /// A receive FIFO dropped a frame since the last read.
+    pub data_overrun: bool,
+    /// A frame waiting for a transmit mailbox was dropped since the last read.
+    pub transmit_dropped: bool,
+}
+
+/// Kind of the last error detected on the bus, as held in the ESR register.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LastErrorCode {
+    /// No error since the code was last cleared.
+    #[default]
+    None = 0,
+    Stuff = 1,
+    Form = 2,
+    Acknowledgement = 3,
+    /// A recessive bit was sent, but a dominant bit was monitored.
+    BitRecessive = 4,
+    /// A dominant bit was sent, but a recessive bit was monitored.
+    BitDominant = 5,
+    Crc = 6,
+}
+
+impl LastErrorCode {
+    /// Decodes the LEC field, the value reserved for software decodes as `None`.
+    fn from_lec(lec: u32) -> Self {
+        match lec {
+            1 => LastErrorCode::Stuff,
+            2 => LastErrorCode::Form,
+            3 => LastErrorCode::Acknowledgement,
+            4 => LastErrorCode::BitRecessive,
+            5 => LastErrorCode::BitDominant,
+            6 => LastErrorCode::Crc,
+            _ => LastErrorCode::None,
+        }
+    }
+}
+
+/// Error counters and last error code of the CAN controller, for diagnosing the bus.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ErrorState {
+    pub transmit_error_count: u8,
+    pub receive_error_count: u8,
+    pub last_error: LastErrorCode,
+}
+
+/// Controller test modes, for verifying the adapter without a transceiver or bus.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TestMode {
+    /// Normal operation.
+    Normal,
+    /// Transmitted frames are received back, and also sent on the bus.
+    Loopback,
+    /// Transmitted frames are only received back, the bus is neither driven nor monitored.
+    SilentLoopback,
+}
+
+/// How the controller returns to the bus after going bus-off.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BusOffRecovery {
+    /// The controller recovers by itself once the bus has been idle for long enough.
+    Automatic,
+    /// The controller stays off the bus until the channel is opened again.
+    Manual,
+    /// Recovery is started after the given number of milliseconds off the bus.
+    Delayed(u16),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum CANBitrate {
     Bitrate10k,
     Bitrate20k,
@@ -25,19 +141,132 @@ pub enum CANBitrate {
     Bitrate1M,
 }
 
+impl CANBitrate {
+    const ALL: [CANBitrate; 9] = [
+        CANBitrate::Bitrate10k,
+        CANBitrate::Bitrate20k,
+        CANBitrate::Bitrate50k,
+        CANBitrate::Bitrate100k,
+        CANBitrate::Bitrate125k,
+        CANBitrate::Bitrate250k,
+        CANBitrate::Bitrate500k,
+        CANBitrate::Bitrate800k,
+        CANBitrate::Bitrate1M,
+    ];
+
+    /// Decodes the bit rate code of the Lawicel `S` command, e.g. 6 for 500 kbit/s.
+    pub fn from_code(code: u8) -> Option<Self> {
+        CANBitrate::ALL.get(usize::from(code)).copied()
+    }
+
+    /// Returns the bit rate code of the Lawicel `S` command.
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    /// Returns the bit rate in bits per second.
+    pub fn hz(&self) -> u32 {
+        match self {
+            CANBitrate::Bitrate10k => 10_000,
+            CANBitrate::Bitrate20k => 20_000,
+            CANBitrate::Bitrate50k => 50_000,
+            CANBitrate::Bitrate100k => 100_000,
+            CANBitrate::Bitrate125k => 125_000,
+            CANBitrate::Bitrate250k => 250_000,
+            CANBitrate::Bitrate500k => 500_000,
+            CANBitrate::Bitrate800k => 800_000,
+            CANBitrate::Bitrate1M => 1_000_000,
+        }
+    }
+}
+
+/// CAN controller operations needed by the SLCAN protocol layer.
+pub trait CanDriver {
+    /// Queues a frame for transmission. Fails if the transmit queue is full.
+    /// Returns a lower priority frame which had to be dropped to make room for it, if any.
+    fn transmit(&mut self, frame: &Frame) -> Result<Option<Frame>, CANError>;
+
+    /// Receives a frame. Returns `Err` when a frame was lost due to an overrun.
+    fn receive(&mut self) -> nb::Result<Frame, CANError>;
+
+    /// Sets one of the standard bit rates, leaving the controller disabled.
+    fn set_bitrate(&mut self, bitrate: CANBitrate) -> Result<(), CANError>;
+
+    /// Sets the bit timing from a raw bxCAN BTR register value, leaving the controller disabled.
+    fn set_raw_bit_timing(&mut self, btr: u32) -> Result<(), CANError>;
+
+    /// Returns the frequency of the clock that bit timings are derived from, in Hz.
+    fn clock_hz(&self) -> u32;
+
+    /// Configures acceptance filtering from an SJA1000 acceptance code and mask.
+    fn set_acceptance_filter(&mut self, code: u32, mask: u32);
+
+    fn is_enabled(&self) -> bool;
+
+    /// Selects silent (listen-only) mode, in which the controller neither transmits frames
+    /// nor acknowledges frames on the bus.
+    fn set_silent(&mut self, silent: bool);
+
+    fn is_silent(&self) -> bool;
+
+    /// Selects a loopback test mode. Silent loopback applies regardless of silent mode.
+    fn set_test_mode(&mut self, mode: TestMode);
+
+    fn test_mode(&self) -> TestMode;
+
+    /// Selects how the controller recovers from bus-off, applied when next enabled.
+    fn set_bus_off_recovery(&mut self, recovery: BusOffRecovery);
+
+    fn bus_off_recovery(&self) -> BusOffRecovery;
+
+    /// Returns whether the controller is currently in bus-off state.
+    fn is_bus_off(&self) -> bool;
+
+    /// Returns whether the controller has gone bus-off since the last call, even if it has
+    /// recovered since, as automatic recovery takes only milliseconds.
+    fn take_bus_off_event(&mut self) -> bool;
+
+    /// Enables the controller. If it is bus-off, this starts its recovery.
+    fn enable(&mut self);
+
+    fn disable(&mut self);
+
+    /// Reads the error and status conditions, clearing latched conditions.
+    fn status(&mut self) -> BusStatus;
+
+    /// Reads the error counters and the last error code, without clearing anything.
+    fn error_state(&self) -> ErrorState;
+}
+
 pub struct CANBus<I>
 where
     I: bxcan::FilterOwner,
 {
     can_instance: bxcan::Can<I>,
+    clock_hz: u32,
     enabled: bool,
+    silent: bool,
+    test_mode: TestMode,
+    bus_off_recovery: BusOffRecovery,
+    /// Frames waiting for a free transmit mailbox, in the order they were queued.
+    pending: heapless::Deque<Frame, TRANSMIT_QUEUE_LEN>,
+    overrun: bool,
+    /// A frame displaced from its mailbox could not be queued again.
+    transmit_dropped: bool,
+    arbitration_lost: bool,
+    /// The controller went bus-off, as latched by the status change interrupt.
+    went_bus_off: bool,
+    /// Last error code, kept after `status` marks it as seen in the controller.
+    last_error: LastErrorCode,
 }
 
 impl<I> CANBus<I>
 where
     I: bxcan::FilterOwner,
 {
-    pub fn new(can: I) -> Self {
+    /// Creates the bus interface. `clock_hz` is the frequency of the clock driving the CAN
+    /// peripheral (APB1), which all bit timings are derived from.
+    pub fn new(can: I, clock_hz: u32) -> Self {
         let mut bxcan = bxcan::Can::builder(can).leave_disabled();
         let mut filters = bxcan.modify_filters();
         filters.enable_bank(0, Mask32::accept_all());
@@ -45,57 +274,549 @@ where
 
         CANBus {
             can_instance: bxcan,
+            clock_hz,
             enabled: false,
+            silent: false,
+            test_mode: TestMode::Normal,
+            bus_off_recovery: BusOffRecovery::Automatic,
+            pending: heapless::Deque::new(),
+            overrun: false,
+            transmit_dropped: false,
+            arbitration_lost: false,
+            went_bus_off: false,
+            last_error: LastErrorCode::None,
+        }
+    }
+
+    /// Enables the given controller interrupt sources.
+    pub fn enable_interrupts(&mut self, interrupts: Interrupts) {
+        self.can_instance.enable_interrupts(interrupts);
+    }
+
+    /// Disables the given controller interrupt sources.
+    pub fn disable_interrupts(&mut self, interrupts: Interrupts) {
+        self.can_instance.disable_interrupts(interrupts);
+    }
+
+    /// Enables the status change interrupt on going bus-off, whose handler must call
+    /// `service_status_change`.
+    pub fn enable_bus_off_interrupt(&mut self) {
+        self.can_instance.enable_interrupts(Interrupts::ERROR);
+        let ier = self.read_register(IER_OFFSET);
+        self.write_register(IER_OFFSET, ier | IER_BOFIE);
+    }
+
+    /// Latches bus-off for `take_bus_off_event`, before automatic recovery can clear it.
+    /// Must be called from the status change (SCE) interrupt.
+    pub fn service_status_change(&mut self) {
+        if self.read_register(ESR_OFFSET) & ESR_BOFF != 0 {
+            self.went_bus_off = true;
+        }
+        self.write_register(MSR_OFFSET, MSR_ERRI);
+    }
+
+    /// Refills the transmit mailboxes from the software transmit queue.
+    /// Must be called from the transmit mailbox empty interrupt.
+    pub fn service_transmit(&mut self) {
+        let tsr = self.read_register(TSR_OFFSET);
+        for mailbox in 0..3 {
+            if tsr & TSR_RQCP[mailbox] != 0 {
+                // ALST is cleared along with the request completed flag, keep it for `status`
+                if tsr & TSR_ALST[mailbox] != 0 {
+                    self.arbitration_lost = true;
+                }
+                self.write_register(TSR_OFFSET, TSR_RQCP[mailbox]);
+            }
+        }
+
+        // only use free mailboxes, so that no pending frame is displaced
+        let can_instance = &mut self.can_instance;
+        let requeued = refill_mailboxes(
+            &mut self.pending,
+            || Self::read_owned_register(TSR_OFFSET) & TSR_TME != 0,
+            |frame| {
+                can_instance
+                    .transmit(frame)
+                    .map(|status| status.dequeued_frame().cloned())
+            },
+        );
+        if !requeued {
+            self.transmit_dropped = true;
+        }
+    }
+
+    /// Writes the silent and loopback mode bits, keeping the controller enabled if it was.
+    fn apply_mode(&mut self) {
+        let silent = self.silent || self.test_mode == TestMode::SilentLoopback;
+        let loopback = self.test_mode != TestMode::Normal;
+
+        let config = self
+            .can_instance
+            .modify_config()
+            .set_silent(silent)
+            .set_loopback(loopback);
+        if self.enabled {
+            config.enable();
+            self.apply_bus_off_recovery();
+        } else {
+            config.leave_disabled();
+        }
+    }
+
+    /// Writes the automatic bus-off management bit, which bxcan always sets when enabling.
+    fn apply_bus_off_recovery(&mut self) {
+        let mcr = self.read_register(MCR_OFFSET);
+        let mcr = match self.bus_off_recovery {
+            BusOffRecovery::Automatic => mcr | MCR_ABOM,
+            BusOffRecovery::Manual | BusOffRecovery::Delayed(_) => mcr & !MCR_ABOM,
+        };
+        self.write_register(MCR_OFFSET, mcr);
+    }
+
+    fn read_register(&self, offset: usize) -> u32 {
+        Self::read_owned_register(offset)
+    }
+
+    /// Reads a register while `can_instance` is borrowed. Only for use by the owner.
+    fn read_owned_register(offset: usize) -> u32 {
+        // safety: the register block is owned by `can_instance`, and `offset` is a valid register
+        unsafe { core::ptr::read_volatile((I::REGISTERS as *const u8).add(offset) as *const u32) }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32) {
+        // safety: as for `read_register`, `&mut self` ensures exclusive access
+        unsafe {
+            core::ptr::write_volatile((I::REGISTERS as *mut u8).add(offset) as *mut u32, value)
+        }
+    }
+
+    fn get_bit_timings(&self, bitrate: CANBitrate) -> Result<u32, CANError> {
+        timing::calculate(self.clock_hz, bitrate.hz())
+            .map(|timing| timing.bxcan())
+            .ok_or(CANError::Regular(ErrorKind::InvalidTiming))
+    }
+}
+
+impl<I> CanDriver for CANBus<I>
+where
+    I: bxcan::FilterOwner,
+{
+    /// Puts the frame in a transmit mailbox, or in the software queue while the mailboxes
+    /// are full. A higher priority frame may displace a lower priority one from its mailbox,
+    /// which then waits at the front of the queue.
+    fn transmit(&mut self, frame: &Frame) -> Result<Option<Frame>, CANError> {
+        // keep the order of frames queued behind those already waiting
+        if !self.pending.is_empty() {
+            return self
+                .pending
+                .push_back(frame.clone())
+                .map(|()| None)
+                .map_err(|_| CANError::Regular(ErrorKind::BufferOverrun));
+        }
+
+        match self.can_instance.transmit(frame) {
+            Ok(status) => Ok(status
+                .dequeued_frame()
+                .and_then(|displaced| self.pending.push_front(displaced.clone()).err())),
+            Err(nb::Error::WouldBlock) => self
+                .pending
+                .push_back(frame.clone())
+                .map(|()| None)
+                .map_err(|_| CANError::Regular(ErrorKind::BufferOverrun)),
+            Err(nb::Error::Other(never)) => match never {},
+        }
+    }
+
+    /// Receives a frame from either FIFO.
+    /// Returns `Err` when a frame was lost due to a FIFO overrun.
+    fn receive(&mut self) -> nb::Result<Frame, CANError> {
+        self.can_instance.receive().map_err(|e| match e {
+            nb::Error::WouldBlock => nb::Error::WouldBlock,
+            nb::Error::Other(()) => {
+                self.overrun = true;
+                nb::Error::Other(CANError::Regular(ErrorKind::BufferOverrun))
+            }
+        })
+    }
+
+    /// Reads the error and status conditions from the controller, clearing latched conditions.
+    fn status(&mut self) -> BusStatus {
+        let esr = self.read_register(ESR_OFFSET);
+        let lec = (esr & ESR_LEC_MASK) >> ESR_LEC_SHIFT;
+        if lec != LEC_UNSET {
+            self.last_error = LastErrorCode::from_lec(lec);
+        }
+        if lec != 0 {
+            // mark the error code as seen, the controller overwrites it on the next error or success
+            self.write_register(ESR_OFFSET, LEC_UNSET << ESR_LEC_SHIFT);
+        }
+
+        let tsr = self.read_register(TSR_OFFSET);
+        let mut arbitration_lost = self.arbitration_lost;
+        self.arbitration_lost = false;
+        for mailbox in 0..3 {
+            if tsr & TSR_ALST[mailbox] != 0 {
+                arbitration_lost = true;
+                // ALST is cleared along with the request completed flag
+                self.write_register(TSR_OFFSET, TSR_RQCP[mailbox]);
+            }
+        }
+
+        let mut data_overrun = self.overrun;
+        self.overrun = false;
+        for offset in [RF0R_OFFSET, RF1R_OFFSET] {
+            if self.read_register(offset) & RFR_FOVR != 0 {
+                data_overrun = true;
+                self.write_register(offset, RFR_FOVR);
+            }
+        }
+
+        BusStatus {
+            error_warning: esr & ESR_EWGF != 0,
+            error_passive: esr & ESR_EPVF != 0,
+            bus_off: esr & ESR_BOFF != 0,
+            bus_error: lec != 0 && lec != LEC_UNSET,
+            arbitration_lost,
+            data_overrun,
+            transmit_dropped: core::mem::take(&mut self.transmit_dropped),
         }
     }
 
-    pub fn transmit(&mut self, frame: &Frame) -> Result<TransmitStatus, CANError> {
-        self.can_instance
-            .transmit(frame)
-            .map_err(|_| -> CANError { CANError::Regular(ErrorKind::BufferOverrun) })
+    /// Reads the error counters and last error code from the controller. An error code
+    /// already reported by `status` is kept until the controller records a new one.
+    fn error_state(&self) -> ErrorState {
+        let esr = self.read_register(ESR_OFFSET);
+        let lec = (esr & ESR_LEC_MASK) >> ESR_LEC_SHIFT;
+        let last_error = if lec == LEC_UNSET {
+            self.last_error
+        } else {
+            LastErrorCode::from_lec(lec)
+        };
+
+        ErrorState {
+            transmit_error_count: (esr >> ESR_TEC_SHIFT) as u8,
+            receive_error_count: (esr >> ESR_REC_SHIFT) as u8,
+            last_error,
+        }
     }
 
-    pub fn receive(&mut self) -> Result<Frame, CANError> {
-        self.can_instance
-            .receive()
-            .map_err(|_| -> CANError { CANError::Regular(ErrorKind::BufferOverrun) })
+    fn set_bitrate(&mut self, bitrate: CANBitrate) -> Result<(), CANError> {
+        let timings = self.get_bit_timings(bitrate)?;
+        self.set_raw_bit_timing(timings)
     }
 
-    pub fn set_bitrate(&mut self, bitrate: CANBitrate) -> Result<(), CANError> {
-        let timings = CANBus::<I>::get_bit_timings(bitrate)?;
+    /// Sets the bit timing from a raw bxCAN BTR register value.
+    /// Any bits outside of the timing fields are rejected.
+    fn set_raw_bit_timing(&mut self, btr: u32) -> Result<(), CANError> {
+        if btr & !BTR_TIMING_MASK != 0 {
+            return Err(CANError::Regular(ErrorKind::InvalidTiming));
+        }
 
         self.enabled = false;
         let config = self.can_instance.modify_config();
-        config.set_bit_timing(timings).leave_disabled();
+        config.set_bit_timing(btr).leave_disabled();
 
         Ok(())
     }
 
-    pub fn is_enabled(&self) -> bool {
+    /// Returns the input clock frequency of the CAN peripheral, in Hz.
+    fn clock_hz(&self) -> u32 {
+        self.clock_hz
+    }
+
+    /// Configures hardware acceptance filtering from an SJA1000 acceptance code and mask,
+    /// as set by the Lawicel `M` and `m` commands.
+    ///
+    /// The SJA1000 is used in dual filter mode: a frame is accepted if it matches either of
+    /// two filters, and mask bits set to 1 are "don't care". bxCAN filters cannot compare
+    /// data bytes, so the data byte part of the first standard frame filter is ignored.
+    fn set_acceptance_filter(&mut self, code: u32, mask: u32) {
+        let sja1000_filters = [
+            ((code >> 16) as u16, (mask >> 16) as u16),
+            (code as u16, mask as u16),
+        ];
+
+        let mut filters = self.can_instance.modify_filters();
+        for (bank, (code, mask)) in (0u8..).zip(sja1000_filters) {
+            filters.enable_bank(bank, sja1000_standard_filter(code, mask));
+            filters.enable_bank(bank + 2, sja1000_extended_filter(code, mask));
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
         self.enabled
     }
 
-    pub fn enable(&mut self) {
+    fn set_silent(&mut self, silent: bool) {
+        self.silent = silent;
+        self.apply_mode();
+    }
+
+    fn is_silent(&self) -> bool {
+        self.silent
+    }
+
+    fn set_test_mode(&mut self, mode: TestMode) {
+        self.test_mode = mode;
+        self.apply_mode();
+    }
+
+    fn test_mode(&self) -> TestMode {
+        self.test_mode
+    }
+
+    fn set_bus_off_recovery(&mut self, recovery: BusOffRecovery) {
+        self.bus_off_recovery = recovery;
+    }
+
+    fn bus_off_recovery(&self) -> BusOffRecovery {
+        self.bus_off_recovery
+    }
+
+    fn is_bus_off(&self) -> bool {
+        self.read_register(ESR_OFFSET) & ESR_BOFF != 0
+    }
+
+    fn take_bus_off_event(&mut self) -> bool {
+        core::mem::take(&mut self.went_bus_off)
+    }
+
+    /// Enables the controller. Passing through initialization mode starts the recovery
+    /// sequence if the controller is bus-off.
+    fn enable(&mut self) {
         self.can_instance.modify_config().enable();
+        self.apply_bus_off_recovery();
         self.enabled = true;
     }
 
-    pub fn disable(&mut self) {
+    /// Disables the controller, dropping any frames still waiting to be sent.
+    fn disable(&mut self) {
         self.enabled = false;
+        self.pending.clear();
         self.can_instance.modify_config().leave_disabled();
     }
+}
 
-    fn get_bit_timings(bitrate: CANBitrate) -> Result<u32, CANError> {
-        match bitrate {
-            CANBitrate::Bitrate10k => Ok(can_timings_bxcan!(8.mhz(), 10.khz())),
-            CANBitrate::Bitrate20k => Ok(can_timings_bxcan!(8.mhz(), 20.khz())),
-            CANBitrate::Bitrate50k => Ok(can_timings_bxcan!(8.mhz(), 50.khz())),
-            CANBitrate::Bitrate100k => Ok(can_timings_bxcan!(8.mhz(), 100.khz())),
-            CANBitrate::Bitrate125k => Ok(can_timings_bxcan!(8.mhz(), 125.khz())),
-            CANBitrate::Bitrate250k => Ok(can_timings_bxcan!(8.mhz(), 250.khz())),
-            CANBitrate::Bitrate500k => Ok(can_timings_bxcan!(8.mhz(), 500.khz())),
-            CANBitrate::Bitrate800k => Ok(can_timings_bxcan!(8.mhz(), 800.khz())),
-            CANBitrate::Bitrate1M => Err(CANError::Regular(ErrorKind::InvalidTiming)),
+/// Moves frames from the front of `pending` into free transmit mailboxes, keeping their order.
+/// The controller refuses a frame while a mailbox holds one of the same or higher priority,
+/// as it would not send them in order. The frame then stays at the front of the queue until
+/// a mailbox completes. Returns `false` if a frame displaced from its mailbox was lost.
+fn refill_mailboxes<const N: usize>(
+    pending: &mut heapless::Deque<Frame, N>,
+    mut mailbox_free: impl FnMut() -> bool,
+    mut transmit: impl FnMut(&Frame) -> nb::Result<Option<Frame>, Infallible>,
+) -> bool {
+    while mailbox_free() {
+        let Some(frame) = pending.pop_front() else {
+            break;
+        };
+        match transmit(&frame) {
+            Ok(None) => {}
+            // only expected with no free mailbox, there is room for it as a frame was taken
+            Ok(Some(displaced)) => {
+                if pending.push_front(displaced).is_err() {
+                    return false;
+                }
+            }
+            Err(nb::Error::WouldBlock) => {
+                // room was made by taking it
+                pending.push_front(frame).unwrap();
+                break;
+            }
+            Err(nb::Error::Other(never)) => match never {},
         }
     }
+    true
+}
+
+/// Converts one SJA1000 dual mode filter to a bxCAN filter for standard frames.
+/// ID.10-0 occupy the top 11 bits, followed by the RTR bit.
+fn sja1000_standard_filter(code: u16, mask: u16) -> Mask32 {
+    // SJA1000 mask bits are set for "don't care", bxCAN mask bits are set for "must match"
+    let mask = !mask;
+    let id = StandardId::new(code >> 5).unwrap();
+    let id_mask = StandardId::new(mask >> 5).unwrap();
+
+    let mut filter = Mask32::frames_with_std_id(id, id_mask);
+    if mask & 0x10 != 0 {
+        if code & 0x10 != 0 {
+            filter.remote_frames_only();
+        } else {
+            filter.data_frames_only();
+        }
+    }
+    filter
+}
+
+/// Converts one SJA1000 dual mode filter to a bxCAN filter for extended frames.
+/// Only ID.28-13 are compared.
+fn sja1000_extended_filter(code: u16, mask: u16) -> Mask32 {
+    let id = ExtendedId::new(u32::from(code) << 13).unwrap();
+    let id_mask = ExtendedId::new(u32::from(!mask) << 13).unwrap();
+
+    Mask32::frames_with_ext_id(id, id_mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_repr(filter: Mask32) -> std::string::String {
+        std::format!("{:?}", filter)
+    }
+
+    #[test]
+    fn converts_lawicel_bitrate_codes() {
+        assert_eq!(CANBitrate::from_code(0), Some(CANBitrate::Bitrate10k));
+        assert_eq!(CANBitrate::from_code(6), Some(CANBitrate::Bitrate500k));
+        assert_eq!(CANBitrate::from_code(9), None);
+        for code in 0..9 {
+            assert_eq!(CANBitrate::from_code(code).unwrap().code(), code);
+        }
+    }
+
+    #[test]
+    fn decodes_last_error_code() {
+        assert_eq!(LastErrorCode::from_lec(0), LastErrorCode::None);
+        assert_eq!(LastErrorCode::from_lec(3), LastErrorCode::Acknowledgement);
+        assert_eq!(LastErrorCode::from_lec(6), LastErrorCode::Crc);
+        assert_eq!(LastErrorCode::from_lec(LEC_UNSET), LastErrorCode::None);
+        for lec in 0..LEC_UNSET {
+            assert_eq!(LastErrorCode::from_lec(lec) as u32, lec);
+        }
+    }
+
+    /// Transmit mailboxes which, like bxCAN's, refuse a frame while one of the same or higher
+    /// priority is waiting.
+    struct Mailboxes(core::cell::RefCell<[Option<Frame>; 3]>);
+
+    impl Mailboxes {
+        fn new(frames: [Option<Frame>; 3]) -> Self {
+            Mailboxes(core::cell::RefCell::new(frames))
+        }
+
+        fn refill<const N: usize>(&self, pending: &mut heapless::Deque<Frame, N>) -> bool {
+            refill_mailboxes(
+                pending,
+                || self.0.borrow().iter().any(Option::is_none),
+                |frame| {
+                    let mut mailboxes = self.0.borrow_mut();
+                    if mailboxes
+                        .iter()
+                        .flatten()
+                        .any(|waiting| waiting.priority() >= frame.priority())
+                    {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    *mailboxes.iter_mut().find(|m| m.is_none()).unwrap() = Some(frame.clone());
+                    Ok(None)
+                },
+            )
+        }
+
+        fn complete(&self, mailbox: usize) -> Frame {
+            self.0.borrow_mut()[mailbox].take().unwrap()
+        }
+
+        fn ids(&self) -> [Option<u16>; 3] {
+            self.0
+                .borrow()
+                .clone()
+                .map(|frame| frame.map(|frame| std_id(&frame)))
+        }
+    }
+
+    fn frame(id: u16) -> Frame {
+        Frame::new_data(StandardId::new(id).unwrap(), [id as u8])
+    }
+
+    fn std_id(frame: &Frame) -> u16 {
+        match frame.id() {
+            bxcan::Id::Standard(id) => id.as_raw(),
+            bxcan::Id::Extended(_) => unreachable!(),
+        }
+    }
+
+    fn pending_ids<const N: usize>(pending: &heapless::Deque<Frame, N>) -> std::vec::Vec<u16> {
+        pending.iter().map(std_id).collect()
+    }
+
+    #[test]
+    fn keeps_frames_refused_by_mailboxes() {
+        // a stream of frames with the same ID
+        let mailboxes = Mailboxes::new([Some(frame(0x100)), None, None]);
+        let mut pending = heapless::Deque::<Frame, 4>::new();
+        for _ in 0..3 {
+            pending.push_back(frame(0x100)).unwrap();
+        }
+        assert!(mailboxes.refill(&mut pending));
+        assert_eq!(pending.len(), 3);
+        assert_eq!(mailboxes.ids(), [Some(0x100), None, None]);
+
+        mailboxes.complete(0);
+        assert!(mailboxes.refill(&mut pending));
+        assert_eq!(pending.len(), 2);
+        assert_eq!(mailboxes.ids(), [Some(0x100), None, None]);
+    }
+
+    #[test]
+    fn refills_in_order_after_displacement() {
+        // 0x102 was displaced from its mailbox by 0x050, and waits ahead of 0x300
+        let mailboxes =
+            Mailboxes::new([Some(frame(0x100)), Some(frame(0x101)), Some(frame(0x050))]);
+        let mut pending = heapless::Deque::<Frame, 4>::new();
+        pending.push_back(frame(0x102)).unwrap();
+        pending.push_back(frame(0x300)).unwrap();
+
+        assert_eq!(std_id(&mailboxes.complete(2)), 0x050);
+        assert!(mailboxes.refill(&mut pending));
+        assert_eq!(pending_ids(&pending), [0x102, 0x300]);
+
+        mailboxes.complete(0);
+        mailboxes.complete(1);
+        assert!(mailboxes.refill(&mut pending));
+        assert_eq!(pending_ids(&pending), [0x300]);
+        assert_eq!(mailboxes.ids(), [Some(0x102), None, None]);
+
+        mailboxes.complete(0);
+        assert!(mailboxes.refill(&mut pending));
+        assert!(pending.is_empty());
+        assert_eq!(mailboxes.ids(), [Some(0x300), None, None]);
+    }
+
+    #[test]
+    fn sja1000_default_filter_accepts_all_ids() {
+        // only the IDE bit is compared
+        assert_eq!(
+            filter_repr(sja1000_standard_filter(0x0000, 0xFFFF)),
+            "Mask32 { id: 0, mask: 4 }"
+        );
+        assert_eq!(
+            filter_repr(sja1000_extended_filter(0x0000, 0xFFFF)),
+            "Mask32 { id: 4, mask: 4 }"
+        );
+    }
+
+    #[test]
+    fn sja1000_standard_filter_matches_id_and_rtr() {
+        // ID 0x123, data frames only
+        let filter = sja1000_standard_filter(0x123 << 5, 0x000F);
+        let mut expected = Mask32::frames_with_std_id(
+            StandardId::new(0x123).unwrap(),
+            StandardId::new(0x7FF).unwrap(),
+        );
+        expected.data_frames_only();
+        assert_eq!(filter_repr(filter), filter_repr(expected));
+    }
+
+    #[test]
+    fn sja1000_extended_filter_matches_upper_id_bits() {
+        // ID.28-13 = 0x1234, ID.12-0 not compared
+        let filter = sja1000_extended_filter(0x1234, 0x0000);
+        let expected = Mask32::frames_with_ext_id(
+            ExtendedId::new(0x1234 << 13).unwrap(),
+            ExtendedId::new(0xFFFF << 13).unwrap(),
+        );
+        assert_eq!(filter_repr(filter), filter_repr(expected));
+    }
 }
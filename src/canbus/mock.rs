@@ -0,0 +1,170 @@
+use super::{
+    timing, BusOffRecovery, BusStatus, CANBitrate, CANError, CanDriver, ErrorKind, ErrorState,
+    TestMode, BTR_TIMING_MASK,
+};
+use bxcan::Frame;
+
+/// Software CAN driver for host-side tests.
+/// Records frames transmitted onto the bus and receives frames injected by the test,
+/// or looped back in a test mode.
+pub struct MockCanDriver {
+    clock_hz: u32,
+    transmitted: heapless::Vec<Frame, 32>,
+    received: heapless::Deque<Frame, 32>,
+    /// BTR timing fields last set through the driver.
+    pub bit_timing: Option<u32>,
+    /// SJA1000 acceptance code and mask last applied.
+    pub acceptance_filter: Option<(u32, u32)>,
+    /// Rejects transmitted frames as if the transmit queue were full.
+    pub queue_full: bool,
+    /// Frame reported as dropped to make room by the next transmit.
+    pub displaced_frame: Option<Frame>,
+    /// Conditions reported by the next status read.
+    pub bus_status: BusStatus,
+    /// Error counters and last error code reported by the controller.
+    pub error_state: ErrorState,
+    /// Puts the controller in bus-off state, until it is next enabled.
+    pub bus_off: bool,
+    /// Records that the controller went bus-off, as the status change interrupt does.
+    pub went_bus_off: bool,
+    enabled: bool,
+    silent: bool,
+    test_mode: TestMode,
+    bus_off_recovery: BusOffRecovery,
+}
+
+impl MockCanDriver {
+    /// Creates a disabled driver whose bit timings are derived from `clock_hz`.
+    pub fn new(clock_hz: u32) -> Self {
+        MockCanDriver {
+            clock_hz,
+            transmitted: heapless::Vec::new(),
+            received: heapless::Deque::new(),
+            bit_timing: None,
+            acceptance_filter: None,
+            queue_full: false,
+            displaced_frame: None,
+            bus_status: BusStatus::default(),
+            error_state: ErrorState::default(),
+            bus_off: false,
+            went_bus_off: false,
+            enabled: false,
+            silent: false,
+            test_mode: TestMode::Normal,
+            bus_off_recovery: BusOffRecovery::Automatic,
+        }
+    }
+
+    /// Queues a frame to be returned by `receive`, as if received from the bus.
+    pub fn inject(&mut self, frame: Frame) {
+        self.received.push_back(frame).unwrap();
+    }
+
+    /// Frames transmitted so far, oldest first.
+    pub fn transmitted(&self) -> &[Frame] {
+        &self.transmitted
+    }
+}
+
+impl CanDriver for MockCanDriver {
+    fn transmit(&mut self, frame: &Frame) -> Result<Option<Frame>, CANError> {
+        if self.queue_full {
+            return Err(CANError::Regular(ErrorKind::BufferOverrun));
+        }
+
+        if self.test_mode != TestMode::Normal {
+            self.received
+                .push_back(frame.clone())
+                .map_err(|_| CANError::Regular(ErrorKind::BufferOverrun))?;
+        }
+
+        // a silent controller never puts frames on the bus
+        if !self.silent && self.test_mode != TestMode::SilentLoopback {
+            self.transmitted
+                .push(frame.clone())
+                .map_err(|_| CANError::Regular(ErrorKind::BufferOverrun))?;
+        }
+        Ok(self.displaced_frame.take())
+    }
+
+    fn receive(&mut self) -> nb::Result<Frame, CANError> {
+        self.received.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+
+    fn set_bitrate(&mut self, bitrate: CANBitrate) -> Result<(), CANError> {
+        let timing = timing::calculate(self.clock_hz, bitrate.hz())
+            .ok_or(CANError::Regular(ErrorKind::InvalidTiming))?;
+        self.set_raw_bit_timing(timing.bxcan())
+    }
+
+    fn set_raw_bit_timing(&mut self, btr: u32) -> Result<(), CANError> {
+        if btr & !BTR_TIMING_MASK != 0 {
+            return Err(CANError::Regular(ErrorKind::InvalidTiming));
+        }
+        self.enabled = false;
+        self.bit_timing = Some(btr);
+        Ok(())
+    }
+
+    fn clock_hz(&self) -> u32 {
+        self.clock_hz
+    }
+
+    fn set_acceptance_filter(&mut self, code: u32, mask: u32) {
+        self.acceptance_filter = Some((code, mask));
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn set_silent(&mut self, silent: bool) {
+        self.silent = silent;
+    }
+
+    fn is_silent(&self) -> bool {
+        self.silent
+    }
+
+    fn set_test_mode(&mut self, mode: TestMode) {
+        self.test_mode = mode;
+    }
+
+    fn test_mode(&self) -> TestMode {
+        self.test_mode
+    }
+
+    fn set_bus_off_recovery(&mut self, recovery: BusOffRecovery) {
+        self.bus_off_recovery = recovery;
+    }
+
+    fn bus_off_recovery(&self) -> BusOffRecovery {
+        self.bus_off_recovery
+    }
+
+    fn is_bus_off(&self) -> bool {
+        self.bus_off
+    }
+
+    fn take_bus_off_event(&mut self) -> bool {
+        core::mem::take(&mut self.went_bus_off)
+    }
+
+    /// Enables the controller, which recovers from bus-off straight away.
+    fn enable(&mut self) {
+        self.enabled = true;
+        self.bus_off = false;
+    }
+
+    fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    fn status(&mut self) -> BusStatus {
+        core::mem::take(&mut self.bus_status)
+    }
+
+    fn error_state(&self) -> ErrorState {
+        self.error_state
+    }
+}
@@ -0,0 +1,170 @@
+use can_bit_timings::CanBitTiming;
+
+/// Time quantum clock of the SJA1000 emulated by the Lawicel `s` command.
+/// The SJA1000 runs from a 16 MHz crystal and its prescaler counts in units of 2 clock periods.
+pub const SJA1000_CLOCK_HZ: u32 = 8_000_000;
+
+/// Sample point used for the standard bit rates, in thousandths of a bit (CiA 301).
+const DEFAULT_SAMPLE_POINT: u32 = 875;
+
+const MAX_PRESCALER: u32 = 1024;
+const MAX_SEG1: u32 = 16;
+const MAX_SEG2: u32 = 8;
+const MAX_SJW: u8 = 4;
+const MIN_QUANTA: u32 = 3;
+const MAX_QUANTA: u32 = 1 + MAX_SEG1 + MAX_SEG2;
+
+/// Calculates the timing for `bitrate_hz` on a peripheral clocked at `clock_hz`.
+///
+/// Returns `None` if the bit rate cannot be realised exactly.
+pub fn calculate(clock_hz: u32, bitrate_hz: u32) -> Option<CanBitTiming> {
+    if bitrate_hz == 0 || !clock_hz.is_multiple_of(bitrate_hz) {
+        return None;
+    }
+    split_bit(clock_hz / bitrate_hz, DEFAULT_SAMPLE_POINT, 1)
+}
+
+/// Decodes the SJA1000 BTR0/BTR1 register pair.
+/// Triple sampling (BTR1.7) is not supported by bxCAN and is ignored.
+pub fn from_sja1000(btr0: u8, btr1: u8) -> CanBitTiming {
+    CanBitTiming {
+        bs1: (btr1 & 0x0F) + 1,
+        bs2: ((btr1 >> 4) & 0x07) + 1,
+        sjw: (btr0 >> 6) + 1,
+        prescaler: u16::from(btr0 & 0x3F) + 1,
+    }
+}
+
+/// Converts a timing for a peripheral clocked at `from_hz` to one for a peripheral clocked
+/// at `to_hz`, keeping the exact bit rate and the sample point as close as possible.
+///
+/// Returns `None` if the bit rate cannot be realised exactly at `to_hz`.
+pub fn rescale(timing: &CanBitTiming, from_hz: u32, to_hz: u32) -> Option<CanBitTiming> {
+    let quanta = 1 + u32::from(timing.bs1) + u32::from(timing.bs2);
+    let from_cycles = u64::from(timing.prescaler) * u64::from(quanta);
+
+    // length of one bit in cycles of the new clock
+    let scaled = from_cycles * u64::from(to_hz);
+    if !scaled.is_multiple_of(u64::from(from_hz)) {
+        return None;
+    }
+    let bit_cycles = u32::try_from(scaled / u64::from(from_hz)).ok()?;
+
+    let sample_point = (1 + u32::from(timing.bs1)) * 1000 / quanta;
+    split_bit(bit_cycles, sample_point, timing.sjw)
+}
+
+/// Divides a bit of `bit_cycles` peripheral clock cycles into a prescaler and segments,
+/// choosing the sample point closest to `sample_point` (in thousandths of a bit) and
+/// preferring more time quanta per bit.
+fn split_bit(bit_cycles: u32, sample_point: u32, sjw: u8) -> Option<CanBitTiming> {
+    let mut best: Option<(u32, CanBitTiming)> = None;
+
+    for quanta in (MIN_QUANTA..=MAX_QUANTA).rev() {
+        if !bit_cycles.is_multiple_of(quanta) {
+            continue;
+        }
+        let prescaler = bit_cycles / quanta;
+        if prescaler == 0 || prescaler > MAX_PRESCALER {
+            continue;
+        }
+
+        for seg2 in 1..=MAX_SEG2.min(quanta - 2) {
+            let seg1 = quanta - 1 - seg2;
+            if !(1..=MAX_SEG1).contains(&seg1) {
+                continue;
+            }
+
+            let error = ((1 + seg1) * 1000 / quanta).abs_diff(sample_point);
+            if best.is_none_or(|(best_error, _)| error < best_error) {
+                let timing = CanBitTiming {
+                    bs1: seg1 as u8,
+                    bs2: seg2 as u8,
+                    sjw: sjw.min(seg2 as u8).min(MAX_SJW),
+                    prescaler: prescaler as u16,
+                };
+                best = Some((error, timing));
+            }
+        }
+    }
+
+    best.map(|(_error, timing)| timing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calculates_standard_bit_rates() {
+        for bitrate in [
+            10_000, 20_000, 50_000, 100_000, 125_000, 250_000, 500_000, 800_000, 1_000_000,
+        ] {
+            let timing = calculate(36_000_000, bitrate).unwrap();
+            let quanta = 1 + u32::from(timing.bs1) + u32::from(timing.bs2);
+            assert_eq!(u32::from(timing.prescaler) * quanta * bitrate, 36_000_000);
+        }
+    }
+
+    #[test]
+    fn calculates_1m_at_closest_sample_point() {
+        // 36 MHz / 1 Mbit/s = 36 cycles = 2 * 18 quanta, sampled at 16/18 = 88.9%
+        let timing = calculate(36_000_000, 1_000_000).unwrap();
+        assert_eq!(
+            timing,
+            CanBitTiming {
+                bs1: 15,
+                bs2: 2,
+                sjw: 1,
+                prescaler: 2
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_inexact_bit_rates() {
+        assert_eq!(calculate(45_000_000, 800_000), None);
+        assert_eq!(calculate(8_000_000, 0), None);
+    }
+
+    #[test]
+    fn decodes_sja1000_registers() {
+        // 500 kbit/s, 87.5% sample point
+        let timing = from_sja1000(0x00, 0x1C);
+        assert_eq!(
+            timing,
+            CanBitTiming {
+                bs1: 13,
+                bs2: 2,
+                sjw: 1,
+                prescaler: 1
+            }
+        );
+    }
+
+    #[test]
+    fn rescales_to_same_clock_unchanged() {
+        let timing = from_sja1000(0x00, 0x1C);
+        assert_eq!(
+            rescale(&timing, SJA1000_CLOCK_HZ, SJA1000_CLOCK_HZ),
+            Some(timing)
+        );
+    }
+
+    #[test]
+    fn rescales_to_faster_clock() {
+        // 83.333 kbit/s: 96 cycles at 8 MHz, 432 cycles at 36 MHz
+        let timing = from_sja1000(0x45, 0x2B);
+        let rescaled = rescale(&timing, SJA1000_CLOCK_HZ, 36_000_000).unwrap();
+        let quanta = 1 + u32::from(rescaled.bs1) + u32::from(rescaled.bs2);
+        assert_eq!(u32::from(rescaled.prescaler) * quanta, 432);
+        assert_eq!(rescaled.sjw, 2);
+    }
+
+    #[test]
+    fn rejects_unrealisable_bit_rates() {
+        // 800 kbit/s cannot be divided from 45 MHz
+        let timing = from_sja1000(0x00, 0x16);
+        assert_eq!(rescale(&timing, SJA1000_CLOCK_HZ, 45_000_000), None);
+    }
+}
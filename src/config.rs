@@ -0,0 +1,418 @@
+pub mod mock;
+
+use crate::canbus::CANBitrate;
+use crate::identity::{is_valid_serial_number, SerialNumber};
+
+/// Size of one settings record in the config area.
+pub const RECORD_LEN: usize = 32;
+/// Format of the settings payload. Records of earlier versions are read with the settings
+/// added since at their defaults, and records of later versions are ignored.
+pub const SETTINGS_VERSION: u8 = 2;
+
+const RECORD_MAGIC: u16 = 0xC0F1;
+const HEADER_LEN: usize = 4;
+const CRC_LEN: usize = 4;
+const PAYLOAD_LEN: usize = RECORD_LEN - HEADER_LEN - CRC_LEN;
+/// Value of erased flash.
+const ERASED: u8 = 0xFF;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The flash reported an error while erasing or writing.
+    Flash,
+    /// The record read back differs from the one written.
+    Verify,
+}
+
+/// Non-volatile area holding the settings, e.g. a flash sector.
+/// Erasing sets every byte to 0xFF, and writing can only clear bits.
+pub trait ConfigFlash {
+    /// Size of the area in bytes.
+    fn capacity(&self) -> usize;
+
+    fn read(&self, offset: usize, data: &mut [u8]);
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), ConfigError>;
+
+    /// Erases the whole area.
+    fn erase(&mut self) -> Result<(), ConfigError>;
+}
+
+/// Bit timing selected by the host, kept as given so that it is derived again at startup.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BitTimingSetup {
+    /// One of the standard bit rates, as set by `S`.
+    Bitrate(CANBitrate),
+    /// SJA1000 BTR0 and BTR1 register values, as set by `s`.
+    Sja1000(u8, u8),
+}
+
+/// Whether the channel is opened at startup, as set by the Lawicel `Q` command.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AutoStartup {
+    #[default]
+    Off,
+    Normal,
+    ListenOnly,
+}
+
+/// Adapter settings kept across power cycles.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Settings {
+    pub bit_timing: Option<BitTimingSetup>,
+    pub acceptance_code: u32,
+    pub acceptance_mask: u32,
+    pub timestamps_enabled: bool,
+    pub auto_poll: bool,
+    pub auto_startup: AutoStartup,
+    /// Serial number set by the host, in place of the one derived from the device ID.
+    /// Added in version 2.
+    pub serial_number: Option<SerialNumber>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            bit_timing: None,
+            // accept all frames
+            acceptance_code: 0x0000_0000,
+            acceptance_mask: 0xFFFF_FFFF,
+            timestamps_enabled: false,
+            auto_poll: true,
+            auto_startup: AutoStartup::Off,
+            serial_number: None,
+        }
+    }
+}
+
+impl Settings {
+    fn encode(&self) -> [u8; PAYLOAD_LEN] {
+        let mut payload = [0u8; PAYLOAD_LEN];
+        payload[0..3].copy_from_slice(&match self.bit_timing {
+            None => [0, 0, 0],
+            Some(BitTimingSetup::Bitrate(bitrate)) => [1, bitrate.code(), 0],
+            Some(BitTimingSetup::Sja1000(btr0, btr1)) => [2, btr0, btr1],
+        });
+        payload[3..7].copy_from_slice(&self.acceptance_code.to_le_bytes());
+        payload[7..11].copy_from_slice(&self.acceptance_mask.to_le_bytes());
+        payload[11] = u8::from(self.timestamps_enabled) | u8::from(self.auto_poll) << 1;
+        payload[12] = match self.auto_startup {
+            AutoStartup::Off => 0,
+            AutoStartup::Normal => 1,
+            AutoStartup::ListenOnly => 2,
+        };
+        if let Some(serial_number) = self.serial_number {
+            payload[13] = 1;
+            payload[14..18].copy_from_slice(&serial_number);
+        }
+        payload
+    }
+
+    /// Decodes a payload of any version up to the current one. Unused bytes are written as
+    /// zero, which is the encoding of a setting's default in later versions.
+    fn decode(payload: &[u8; PAYLOAD_LEN]) -> Option<Self> {
+        let bit_timing = match payload[0..3] {
+            [0, _, _] => None,
+            [1, code, _] => Some(BitTimingSetup::Bitrate(CANBitrate::from_code(code)?)),
+            [2, btr0, btr1] => Some(BitTimingSetup::Sja1000(btr0, btr1)),
+            _ => return None,
+        };
+        let auto_startup = match payload[12] {
+            0 => AutoStartup::Off,
+            1 => AutoStartup::Normal,
+            2 => AutoStartup::ListenOnly,
+            _ => return None,
+        };
+        let serial_number = match payload[13] {
+            0 => None,
+            1 if is_valid_serial_number(&payload[14..18]) => {
+                Some(payload[14..18].try_into().unwrap())
+            }
+            _ => return None,
+        };
+
+        Some(Settings {
+            bit_timing,
+            acceptance_code: u32::from_le_bytes(payload[3..7].try_into().unwrap()),
+            acceptance_mask: u32::from_le_bytes(payload[7..11].try_into().unwrap()),
+            timestamps_enabled: payload[11] & 0b01 != 0,
+            auto_poll: payload[11] & 0b10 != 0,
+            auto_startup,
+            serial_number,
+        })
+    }
+}
+
+/// Change to the saved settings requested by the host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SettingsUpdate {
+    /// Replaces all the settings, as `Q` does.
+    All(Settings),
+    /// Replaces only the serial number, keeping the other saved settings.
+    SerialNumber(Option<SerialNumber>),
+}
+
+impl SettingsUpdate {
+    /// Combines this update with a later one, so that saving the result has the effect of
+    /// saving both in turn.
+    pub fn then(self, later: SettingsUpdate) -> SettingsUpdate {
+        match (self, later) {
+            (SettingsUpdate::All(settings), SettingsUpdate::SerialNumber(serial_number)) => {
+                SettingsUpdate::All(Settings {
+                    serial_number,
+                    ..settings
+                })
+            }
+            (_, later) => later,
+        }
+    }
+}
+
+/// Keeps the settings in a flash area as a log of records, each protected by a CRC.
+///
+/// Each save appends a record after the previous one, so the area is only erased once it
+/// is full, and the last valid record is the current one. A record torn by a reset while
+/// being written fails its CRC, and the one before it is used instead.
+pub struct ConfigStore<F: ConfigFlash> {
+    flash: F,
+}
+
+impl<F: ConfigFlash> ConfigStore<F> {
+    pub fn new(flash: F) -> Self {
+        ConfigStore { flash }
+    }
+
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// Returns the settings last saved, or `None` if there are none.
+    pub fn load(&self) -> Option<Settings> {
+        self.scan().0
+    }
+
+    /// Saves the settings, leaving the area untouched if they are unchanged.
+    pub fn save(&mut self, settings: &Settings) -> Result<(), ConfigError> {
+        let (current, free_slot) = self.scan();
+        if current.as_ref() == Some(settings) {
+            return Ok(());
+        }
+
+        let slot = match free_slot {
+            Some(slot) => slot,
+            None => {
+                self.flash.erase()?;
+                0
+            }
+        };
+
+        let record = encode_record(settings);
+        let offset = slot * RECORD_LEN;
+        self.flash.write(offset, &record)?;
+
+        let mut written = [0u8; RECORD_LEN];
+        self.flash.read(offset, &mut written);
+        if written != record {
+            return Err(ConfigError::Verify);
+        }
+        Ok(())
+    }
+
+    /// Applies a change to the saved settings, starting from the defaults if there are none.
+    pub fn update(&mut self, update: &SettingsUpdate) -> Result<(), ConfigError> {
+        let settings = match *update {
+            SettingsUpdate::All(settings) => settings,
+            SettingsUpdate::SerialNumber(serial_number) => Settings {
+                serial_number,
+                ..self.load().unwrap_or_default()
+            },
+        };
+        self.save(&settings)
+    }
+
+    /// Finds the settings of the last valid record, and the first erased slot after it.
+    fn scan(&self) -> (Option<Settings>, Option<usize>) {
+        let mut settings = None;
+        for slot in 0..self.flash.capacity() / RECORD_LEN {
+            let mut record = [0u8; RECORD_LEN];
+            self.flash.read(slot * RECORD_LEN, &mut record);
+            if record.iter().all(|&byte| byte == ERASED) {
+                return (settings, Some(slot));
+            }
+            if let Some(decoded) = decode_record(&record) {
+                settings = Some(decoded);
+            }
+        }
+        (settings, None)
+    }
+}
+
+fn encode_record(settings: &Settings) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[0..2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+    record[2] = SETTINGS_VERSION;
+    record[3] = PAYLOAD_LEN as u8;
+    record[HEADER_LEN..RECORD_LEN - CRC_LEN].copy_from_slice(&settings.encode());
+
+    let crc = crc32(&record[..RECORD_LEN - CRC_LEN]);
+    record[RECORD_LEN - CRC_LEN..].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+fn decode_record(record: &[u8; RECORD_LEN]) -> Option<Settings> {
+    let (data, crc) = record.split_at(RECORD_LEN - CRC_LEN);
+    if crc32(data).to_le_bytes() != crc {
+        return None;
+    }
+    if data[0..2] != RECORD_MAGIC.to_le_bytes()
+        || !(1..=SETTINGS_VERSION).contains(&data[2])
+        || usize::from(data[3]) != PAYLOAD_LEN
+    {
+        return None;
+    }
+    Settings::decode(data[HEADER_LEN..].try_into().unwrap())
+}
+
+/// CRC-32 (IEEE 802.3) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(0xFFFF_FFFF, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockFlash;
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            bit_timing: Some(BitTimingSetup::Bitrate(CANBitrate::Bitrate500k)),
+            acceptance_code: 0x1234_5678,
+            acceptance_mask: 0x0000_FFFF,
+            timestamps_enabled: true,
+            auto_poll: false,
+            auto_startup: AutoStartup::ListenOnly,
+            serial_number: Some(*b"CAN1"),
+        }
+    }
+
+    #[test]
+    fn calculates_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn encodes_settings() {
+        let settings = settings();
+        assert_eq!(Settings::decode(&settings.encode()), Some(settings));
+
+        let settings = Settings {
+            bit_timing: Some(BitTimingSetup::Sja1000(0x00, 0x1C)),
+            ..Settings::default()
+        };
+        assert_eq!(Settings::decode(&settings.encode()), Some(settings));
+        assert_eq!(
+            Settings::decode(&Settings::default().encode()),
+            Some(Settings::default())
+        );
+    }
+
+    #[test]
+    fn reads_version_1_records() {
+        let mut record = encode_record(&Settings {
+            serial_number: None,
+            ..settings()
+        });
+        record[2] = 1;
+        let crc = crc32(&record[..RECORD_LEN - CRC_LEN]);
+        record[RECORD_LEN - CRC_LEN..].copy_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(
+            decode_record(&record),
+            Some(Settings {
+                serial_number: None,
+                ..settings()
+            })
+        );
+    }
+
+    #[test]
+    fn loads_last_saved_settings() {
+        let mut store = ConfigStore::new(MockFlash::<{ 4 * RECORD_LEN }>::new());
+        assert_eq!(store.load(), None);
+
+        store.save(&Settings::default()).unwrap();
+        assert_eq!(store.load(), Some(Settings::default()));
+        store.save(&settings()).unwrap();
+        assert_eq!(store.load(), Some(settings()));
+    }
+
+    #[test]
+    fn appends_records_until_full() {
+        let mut store = ConfigStore::new(MockFlash::<{ 4 * RECORD_LEN }>::new());
+        for code in 0..4 {
+            let settings = Settings {
+                acceptance_code: code,
+                ..Settings::default()
+            };
+            store.save(&settings).unwrap();
+        }
+        assert_eq!(store.flash().erase_count, 0);
+
+        store.save(&settings()).unwrap();
+        assert_eq!(store.flash().erase_count, 1);
+        assert_eq!(store.load(), Some(settings()));
+    }
+
+    #[test]
+    fn skips_unchanged_settings() {
+        let mut store = ConfigStore::new(MockFlash::<{ 4 * RECORD_LEN }>::new());
+        for _ in 0..8 {
+            store.save(&settings()).unwrap();
+        }
+        assert_eq!(store.flash().write_count, 1);
+    }
+
+    #[test]
+    fn ignores_corrupt_and_foreign_records() {
+        let mut store = ConfigStore::new(MockFlash::<{ 4 * RECORD_LEN }>::new());
+        store.save(&settings()).unwrap();
+
+        // a record torn by a reset while being written
+        let torn = encode_record(&Settings::default());
+        store
+            .flash
+            .write(RECORD_LEN, &torn[..RECORD_LEN / 2])
+            .unwrap();
+        assert_eq!(store.load(), Some(settings()));
+
+        // a record of another format version
+        let mut foreign = encode_record(&Settings::default());
+        foreign[2] = SETTINGS_VERSION + 1;
+        let crc = crc32(&foreign[..RECORD_LEN - CRC_LEN]);
+        foreign[RECORD_LEN - CRC_LEN..].copy_from_slice(&crc.to_le_bytes());
+        store.flash.write(2 * RECORD_LEN, &foreign).unwrap();
+        assert_eq!(store.load(), Some(settings()));
+
+        // saving goes on after the unusable records
+        store.save(&Settings::default()).unwrap();
+        assert_eq!(store.load(), Some(Settings::default()));
+        assert_eq!(store.flash().erase_count, 0);
+    }
+
+    #[test]
+    fn reports_failed_writes() {
+        let mut store = ConfigStore::new(MockFlash::<{ 4 * RECORD_LEN }>::new());
+        store.flash.fail_writes = true;
+        assert!(matches!(store.save(&settings()), Err(ConfigError::Verify)));
+        assert_eq!(store.load(), None);
+    }
+}
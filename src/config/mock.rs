@@ -0,0 +1,60 @@
+use super::{ConfigError, ConfigFlash, ERASED};
+
+/// In-memory flash area for host-side tests. Writes clear bits only, as on real flash.
+pub struct MockFlash<const N: usize> {
+    data: [u8; N],
+    /// Number of times the area has been erased.
+    pub erase_count: u32,
+    /// Number of writes so far.
+    pub write_count: u32,
+    /// Leaves the area unchanged on writes, as if programming silently failed.
+    pub fail_writes: bool,
+}
+
+impl<const N: usize> MockFlash<N> {
+    /// Creates an erased area.
+    pub fn new() -> Self {
+        MockFlash {
+            data: [ERASED; N],
+            erase_count: 0,
+            write_count: 0,
+            fail_writes: false,
+        }
+    }
+}
+
+impl<const N: usize> Default for MockFlash<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ConfigFlash for MockFlash<N> {
+    fn capacity(&self) -> usize {
+        N
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        data.copy_from_slice(&self.data[offset..offset + data.len()]);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), ConfigError> {
+        let area = self
+            .data
+            .get_mut(offset..offset + data.len())
+            .ok_or(ConfigError::Flash)?;
+        self.write_count += 1;
+        if !self.fail_writes {
+            for (byte, new) in area.iter_mut().zip(data) {
+                *byte &= new;
+            }
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), ConfigError> {
+        self.data = [ERASED; N];
+        self.erase_count += 1;
+        Ok(())
+    }
+}
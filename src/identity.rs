@@ -0,0 +1,87 @@
+/// Serial number of the adapter, as returned by the Lawicel `N` command.
+pub type SerialNumber = [u8; 4];
+
+/// Identity of the firmware build, reported by the `V` and `v` queries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Board revision, as major and minor nibbles.
+    pub hardware_version: u8,
+    /// Firmware major and minor version, as nibbles.
+    pub software_version: u8,
+    /// Full firmware version.
+    pub version: &'static str,
+    pub board: &'static str,
+    /// Abbreviated hash of the commit built, with `-dirty` appended if it had local changes.
+    pub git_hash: &'static str,
+    /// UTC date of the build, as `YYYY-MM-DD`.
+    pub build_date: &'static str,
+    /// Enabled cargo features, separated by commas, or `none`.
+    pub features: &'static str,
+}
+
+impl BuildInfo {
+    /// Placeholder until the firmware sets its own.
+    pub const UNKNOWN: BuildInfo = BuildInfo {
+        hardware_version: 0x00,
+        software_version: 0x00,
+        version: "unknown",
+        board: "unknown",
+        git_hash: "unknown",
+        build_date: "unknown",
+        features: "none",
+    };
+}
+
+const SERIAL_NUMBER_DIGITS: &[u8; 36] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Derives the serial number from the 96-bit unique device ID of the microcontroller.
+/// The same device always gets the same serial number, and two devices are unlikely to
+/// share one (there are 36^4 of them).
+pub fn serial_number_from_uid(uid: &[u8; 12]) -> SerialNumber {
+    // FNV-1a, spreading the few bits that differ between devices over the whole hash
+    let mut hash = uid.iter().fold(0x811C_9DC5u32, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+
+    let mut serial_number = [0u8; 4];
+    for digit in serial_number.iter_mut().rev() {
+        *digit = SERIAL_NUMBER_DIGITS[(hash % 36) as usize];
+        hash /= 36;
+    }
+    serial_number
+}
+
+/// Returns whether `serial_number` may be used as a serial number: 4 digits or uppercase
+/// letters, as derived from the device ID.
+pub fn is_valid_serial_number(serial_number: &[u8]) -> bool {
+    serial_number.len() == 4
+        && serial_number
+            .iter()
+            .all(|byte| SERIAL_NUMBER_DIGITS.contains(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_stable_serial_numbers() {
+        // changing the hash would renumber every adapter already in use
+        let uid = *b"\x1f\x00\x32\x00\x0dQ3VT41 ";
+        assert_eq!(serial_number_from_uid(&uid), *b"RELI");
+
+        // neighbouring dies on a wafer differ only in their coordinates
+        let mut neighbour = uid;
+        neighbour[0] += 1;
+        assert_eq!(serial_number_from_uid(&neighbour), *b"UDDX");
+    }
+
+    #[test]
+    fn validates_serial_numbers() {
+        assert!(is_valid_serial_number(b"F446"));
+        assert!(is_valid_serial_number(b"0000"));
+        for serial_number in [&b"F44"[..], b"F4460", b"f446", b"F 46", b"F4\r6"] {
+            assert!(!is_valid_serial_number(serial_number));
+        }
+    }
+}
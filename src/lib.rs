@@ -0,0 +1,14 @@
+//! Hardware-independent parts of the adapter: the SLCAN codec and command dispatch,
+//! the CAN controller abstraction and the host transport.
+
+#![cfg_attr(not(test), no_std)]
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+
+pub mod canbus;
+pub mod config;
+pub mod identity;
+pub mod reset;
+pub mod slcan;
+pub mod transport;
+pub mod watchdog;
@@ -0,0 +1,217 @@
+/// Number of trailing bytes of the source file path kept in a panic record.
+pub const PANIC_FILE_LEN: usize = 24;
+
+// Reset flags in the STM32F4 RCC_CSR register
+const CSR_BORRSTF: u32 = 1 << 25;
+const CSR_PINRSTF: u32 = 1 << 26;
+const CSR_PORRSTF: u32 = 1 << 27;
+const CSR_SFTRSTF: u32 = 1 << 28;
+const CSR_IWDGRSTF: u32 = 1 << 29;
+const CSR_WWDGRSTF: u32 = 1 << 30;
+const CSR_LPWRRSTF: u32 = 1 << 31;
+
+/// Why the adapter last started, as reported by the reset flags.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResetCause {
+    PowerOn,
+    Brownout,
+    Pin,
+    Software,
+    IndependentWatchdog,
+    WindowWatchdog,
+    LowPower,
+    Unknown,
+}
+
+impl ResetCause {
+    /// Decodes the reset flags of the RCC_CSR register.
+    /// Several flags are set by most resets (e.g. the NRST pin is driven by every internal
+    /// reset), so the most specific cause is reported.
+    pub fn from_csr(csr: u32) -> Self {
+        if csr & CSR_LPWRRSTF != 0 {
+            ResetCause::LowPower
+        } else if csr & CSR_WWDGRSTF != 0 {
+            ResetCause::WindowWatchdog
+        } else if csr & CSR_IWDGRSTF != 0 {
+            ResetCause::IndependentWatchdog
+        } else if csr & CSR_SFTRSTF != 0 {
+            ResetCause::Software
+        } else if csr & CSR_PORRSTF != 0 {
+            ResetCause::PowerOn
+        } else if csr & CSR_BORRSTF != 0 {
+            ResetCause::Brownout
+        } else if csr & CSR_PINRSTF != 0 {
+            ResetCause::Pin
+        } else {
+            ResetCause::Unknown
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResetCause::PowerOn => "power-on",
+            ResetCause::Brownout => "brownout",
+            ResetCause::Pin => "pin",
+            ResetCause::Software => "software",
+            ResetCause::IndependentWatchdog => "watchdog",
+            ResetCause::WindowWatchdog => "window-watchdog",
+            ResetCause::LowPower => "low-power",
+            ResetCause::Unknown => "unknown",
+        }
+    }
+}
+
+/// Source location of a panic.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PanicLocation {
+    /// End of the source file path, at most `PANIC_FILE_LEN` bytes.
+    pub file: heapless::String<PANIC_FILE_LEN>,
+    pub line: u32,
+}
+
+/// Why the adapter last started, and where it panicked if that was the reason.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResetInfo {
+    pub cause: ResetCause,
+    pub panic: Option<PanicLocation>,
+}
+
+impl ResetInfo {
+    pub fn new(cause: ResetCause, panic: Option<PanicLocation>) -> Self {
+        ResetInfo { cause, panic }
+    }
+}
+
+/// Panic location as kept in RAM which is not initialised at startup, to survive the reset
+/// following a panic. Holds plain integers only, so that whatever RAM contains after
+/// power-on is a valid value, which is then rejected by the magic number and checksum.
+#[repr(C)]
+pub struct PanicRecord {
+    magic: u32,
+    line: u32,
+    file_len: u32,
+    file: [u8; PANIC_FILE_LEN],
+    checksum: u32,
+}
+
+impl PanicRecord {
+    const MAGIC: u32 = 0x5041_4E43;
+
+    /// Records a panic at `line` of `file`, keeping the end of the path if it is too long.
+    pub fn new(file: &str, line: u32) -> Self {
+        let mut start = file.len().saturating_sub(PANIC_FILE_LEN);
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+        let tail = &file.as_bytes()[start..];
+
+        let mut record = PanicRecord {
+            magic: PanicRecord::MAGIC,
+            line,
+            file_len: tail.len() as u32,
+            file: [0; PANIC_FILE_LEN],
+            checksum: 0,
+        };
+        record.file[..tail.len()].copy_from_slice(tail);
+        record.checksum = record.calculate_checksum();
+        record
+    }
+
+    /// A record holding no panic.
+    pub const fn empty() -> Self {
+        PanicRecord {
+            magic: 0,
+            line: 0,
+            file_len: 0,
+            file: [0; PANIC_FILE_LEN],
+            checksum: 0,
+        }
+    }
+
+    /// Returns the recorded location, or `None` if the record holds no valid panic.
+    pub fn location(&self) -> Option<PanicLocation> {
+        if self.magic != PanicRecord::MAGIC || self.checksum != self.calculate_checksum() {
+            return None;
+        }
+        let file = self.file.get(..self.file_len as usize)?;
+        let file = core::str::from_utf8(file).ok()?;
+
+        Some(PanicLocation {
+            file: heapless::String::from(file),
+            line: self.line,
+        })
+    }
+
+    /// FNV-1a hash over all other fields.
+    fn calculate_checksum(&self) -> u32 {
+        let header = [self.magic, self.line, self.file_len];
+        header
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .chain(self.file.iter().copied())
+            .fold(0x811C_9DC5, |hash, byte| {
+                (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_reset_flags() {
+        assert_eq!(
+            ResetCause::from_csr(CSR_PORRSTF | CSR_BORRSTF | CSR_PINRSTF),
+            ResetCause::PowerOn
+        );
+        assert_eq!(
+            ResetCause::from_csr(CSR_BORRSTF | CSR_PINRSTF),
+            ResetCause::Brownout
+        );
+        assert_eq!(ResetCause::from_csr(CSR_PINRSTF), ResetCause::Pin);
+        assert_eq!(
+            ResetCause::from_csr(CSR_SFTRSTF | CSR_PINRSTF),
+            ResetCause::Software
+        );
+        assert_eq!(
+            ResetCause::from_csr(CSR_IWDGRSTF | CSR_PINRSTF),
+            ResetCause::IndependentWatchdog
+        );
+        assert_eq!(ResetCause::from_csr(0), ResetCause::Unknown);
+    }
+
+    #[test]
+    fn records_panic_location() {
+        let record = PanicRecord::new("src/slcan.rs", 42);
+        assert_eq!(
+            record.location(),
+            Some(PanicLocation {
+                file: heapless::String::from("src/slcan.rs"),
+                line: 42,
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_end_of_long_paths() {
+        let path = "/home/user/.cargo/registry/src/heapless/src/vec.rs";
+        let location = PanicRecord::new(path, 7).location().unwrap();
+        assert_eq!(location.file.len(), PANIC_FILE_LEN);
+        assert!(path.ends_with(location.file.as_str()));
+    }
+
+    #[test]
+    fn rejects_invalid_records() {
+        assert_eq!(PanicRecord::empty().location(), None);
+
+        let mut record = PanicRecord::new("src/main.rs", 10);
+        record.line = 11;
+        assert_eq!(record.location(), None);
+
+        let mut record = PanicRecord::new("src/main.rs", 10);
+        record.file_len = 100;
+        record.checksum = record.calculate_checksum();
+        assert_eq!(record.location(), None);
+    }
+}
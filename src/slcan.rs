@@ -1,8 +1,15 @@
 mod util;
 
-use crate::canbus::{CANBitrate, CANBus};
+use crate::canbus::{
+    timing, BusOffRecovery, BusStatus, CANBitrate, CanDriver, ErrorState, TestMode,
+};
+use crate::config::{AutoStartup, BitTimingSetup, Settings, SettingsUpdate};
+use crate::identity::{is_valid_serial_number, BuildInfo, SerialNumber};
+use crate::reset::{ResetCause, ResetInfo};
 use crate::slcan::util::concat;
+use crate::transport::Transport;
 use bxcan::{ExtendedId, StandardId};
+use core::fmt::Write;
 use heapless;
 use hex;
 use packed_struct::prelude::*;
@@ -36,8 +43,26 @@ pub struct QueueFullError;
 
 pub const COMMAND_TERMINATOR: u8 = b'\r';
 pub const ERROR_CHAR: u8 = 7;
+/// Sent to the host, unprompted, when the controller goes bus-off.
+pub const BUS_OFF_NOTIFICATION: &[u8] = b"!bus-off";
+/// Sent to the host, unprompted, when the controller has recovered from bus-off.
+pub const BUS_ON_NOTIFICATION: &[u8] = b"!bus-on";
 
 pub type QueueType = heapless::Deque<u8, 128>;
+/// Ring buffer of bytes waiting to be sent to the host.
+pub type TxQueueType = heapless::Deque<u8, 1024>;
+/// Host UART baud rate at power-on.
+pub const DEFAULT_UART_BAUD_RATE: u32 = 115_200;
+/// Host UART baud rates selected by `Un`, indexed by the hex digit n.
+/// 0 to 6 are defined by the Lawicel spec, the faster rates are extensions.
+pub const UART_BAUD_RATES: [u32; 12] = [
+    230_400, 115_200, 57_600, 38_400, 19_200, 9_600, 2_400, 460_800, 921_600, 1_000_000, 2_000_000,
+    3_000_000,
+];
+/// Number of received frames held for the host while auto-poll is off.
+pub const RECEIVE_FIFO_LEN: usize = 32;
+/// Received frame waiting to be polled, with its timestamp if timestamps were enabled.
+type ReceiveFifoType = heapless::Deque<(bxcan::Frame, Option<Timestamp>), RECEIVE_FIFO_LEN>;
 
 trait HexOutput<const N: usize> {
     fn as_bytes(&self) -> [u8; N];
@@ -46,15 +71,7 @@ trait HexOutput<const N: usize> {
         let mut hex_str = [0u8; N * 2];
         hex::encode_to_slice(self.as_bytes(), &mut hex_str).unwrap();
         hex_str.make_ascii_uppercase();
-        return hex_str;
-    }
-
-    fn push_to_queue(&self, queue: &mut QueueType) -> Result<[u8; N * 2], u8> {
-        let hex_str = self.as_hex();
-        for byte in hex_str {
-            queue.push_back(byte)?;
-        }
-        Ok(hex_str)
+        hex_str
     }
 }
 
@@ -62,14 +79,6 @@ impl HexOutput<2> for StandardId {
     fn as_bytes(&self) -> [u8; 2] {
         self.as_raw().to_be_bytes()
     }
-
-    fn push_to_queue(&self, queue: &mut QueueType) -> Result<[u8; 4], u8> {
-        let hex_str = self.as_hex();
-        for byte in &hex_str[1..] {
-            queue.push_back(*byte)?;
-        }
-        Ok(hex_str)
-    }
 }
 
 impl HexOutput<4> for ExtendedId {
@@ -79,16 +88,24 @@ impl HexOutput<4> for ExtendedId {
 }
 
 #[derive(PackedStruct)]
-#[packed_struct(bit_numbering = "msb0")]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "1")]
 struct StatusFlags {
     #[packed_field(bits = "0")]
     receive_queue_full: bool,
+    #[packed_field(bits = "1")]
     transmit_queue_full: bool,
+    #[packed_field(bits = "2")]
     error_warning: bool,
+    #[packed_field(bits = "3")]
     data_overrun: bool,
+    /// Extension, the bit is unused by the Lawicel spec.
+    #[packed_field(bits = "4")]
+    bus_off: bool,
     #[packed_field(bits = "5")]
     error_passive: bool,
+    #[packed_field(bits = "6")]
     arbitration_lost: bool,
+    #[packed_field(bits = "7")]
     bus_error: bool,
 }
 
@@ -99,52 +116,252 @@ impl StatusFlags {
             transmit_queue_full: false,
             error_warning: false,
             data_overrun: false,
+            bus_off: false,
             error_passive: false,
             arbitration_lost: false,
             bus_error: false,
         }
     }
+
+    /// Merges in the conditions reported by the CAN controller.
+    fn update(&mut self, bus_status: &BusStatus) {
+        self.error_warning |= bus_status.error_warning;
+        self.error_passive |= bus_status.error_passive;
+        self.data_overrun |= bus_status.data_overrun;
+        self.transmit_queue_full |= bus_status.transmit_dropped;
+        self.bus_off |= bus_status.bus_off;
+        self.arbitration_lost |= bus_status.arbitration_lost;
+        self.bus_error |= bus_status.bus_error;
+    }
 }
 
 impl HexOutput<1> for StatusFlags {
     fn as_bytes(&self) -> [u8; 1] {
-        return self.pack().unwrap();
+        self.pack().unwrap()
+    }
+}
+
+impl HexOutput<3> for ErrorState {
+    fn as_bytes(&self) -> [u8; 3] {
+        [
+            self.transmit_error_count,
+            self.receive_error_count,
+            self.last_error as u8,
+        ]
+    }
+}
+
+/// Millisecond timestamp for received frames, wrapping at 60000 as per the Lawicel spec.
+/// Driven from a free-running microsecond counter which may itself wrap at any point.
+#[derive(Clone)]
+pub struct Timestamp {
+    last_us: u32,
+    remainder_us: u32,
+    millis: u16,
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timestamp {
+    pub const WRAP_MS: u16 = 60000;
+
+    pub fn new() -> Self {
+        Timestamp {
+            last_us: 0,
+            remainder_us: 0,
+            millis: 0,
+        }
+    }
+
+    /// Advances the timestamp to `now_us`, returning the current value in milliseconds.
+    /// Must be called at least once per wrap of the microsecond counter.
+    pub fn update(&mut self, now_us: u32) -> u16 {
+        let elapsed_us =
+            u64::from(now_us.wrapping_sub(self.last_us)) + u64::from(self.remainder_us);
+        self.last_us = now_us;
+        self.remainder_us = (elapsed_us % 1000) as u32;
+
+        let millis = u64::from(self.millis) + elapsed_us / 1000;
+        self.millis = (millis % u64::from(Timestamp::WRAP_MS)) as u16;
+        self.millis
     }
 }
 
-struct VersionInfo {
-    hardware_version: u8,
-    software_version: u8,
+impl HexOutput<2> for Timestamp {
+    fn as_bytes(&self) -> [u8; 2] {
+        self.millis.to_be_bytes()
+    }
 }
 
-impl HexOutput<2> for VersionInfo {
+impl HexOutput<2> for BuildInfo {
     fn as_bytes(&self) -> [u8; 2] {
-        return [self.hardware_version, self.software_version];
+        [self.hardware_version, self.software_version]
     }
 }
 
 pub struct SLCAN {
-    pub bitrate: Option<CANBitrate>,
+    /// Bit timing last set by the host, if any.
+    bit_timing: Option<BitTimingSetup>,
+    acceptance_code: u32,
+    acceptance_mask: u32,
     timestamps_enabled: bool,
+    /// Received frames are sent to the host as they arrive, rather than when polled.
+    auto_poll: bool,
+    timestamp: Timestamp,
     status: StatusFlags,
-    version: VersionInfo,
-    serial_number: [u8; 4],
+    build_info: BuildInfo,
+    /// Serial number derived from the device ID.
+    serial_number: SerialNumber,
+    /// Serial number set by the host with `n`, reported in place of `serial_number`.
+    serial_number_override: Option<SerialNumber>,
+    reset_info: ResetInfo,
+    /// Time at which the controller went bus-off, or recovery was last started.
+    bus_off_since_us: Option<u32>,
+    /// Received frames waiting to be polled by the host, while auto-poll is off.
+    rx_fifo: ReceiveFifoType,
+    /// Number of frames from `rx_fifo` to send ahead of the next command output.
+    polled_frames: usize,
+    /// Host baud rate to switch to once the response to `U` has been sent.
+    pending_baud_rate: Option<u32>,
+    /// Settings to be written to flash, as requested by `Q` or `n`.
+    settings_to_save: Option<SettingsUpdate>,
+}
+
+impl Default for SLCAN {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SLCAN {
     pub fn new() -> Self {
         SLCAN {
-            bitrate: None,
+            bit_timing: None,
+            // accept all frames
+            acceptance_code: 0x0000_0000,
+            acceptance_mask: 0xFFFF_FFFF,
             timestamps_enabled: false,
+            auto_poll: true,
+            timestamp: Timestamp::new(),
             status: StatusFlags::new(),
-            version: VersionInfo {
-                hardware_version: 0x01,
-                software_version: 0x01,
-            },
+            build_info: BuildInfo::UNKNOWN,
             serial_number: *b"F446",
+            serial_number_override: None,
+            reset_info: ResetInfo::new(ResetCause::Unknown, None),
+            bus_off_since_us: None,
+            rx_fifo: ReceiveFifoType::new(),
+            polled_frames: 0,
+            pending_baud_rate: None,
+            settings_to_save: None,
+        }
+    }
+
+    /// Records why the adapter last started, for the `?` query.
+    pub fn set_reset_info(&mut self, reset_info: ResetInfo) {
+        self.reset_info = reset_info;
+    }
+
+    /// Describes the firmware, for the `V` and `v` queries.
+    pub fn set_build_info(&mut self, build_info: BuildInfo) {
+        self.build_info = build_info;
+    }
+
+    /// Sets the serial number derived from the device ID, for the `N` query.
+    pub fn set_serial_number(&mut self, serial_number: SerialNumber) {
+        self.serial_number = serial_number;
+    }
+
+    /// Returns the serial number reported to the host, which is the one saved with `n`
+    /// if there is one.
+    pub fn serial_number(&self) -> SerialNumber {
+        self.serial_number_override.unwrap_or(self.serial_number)
+    }
+
+    /// Restores settings saved in flash, opening the channel if set to start automatically.
+    pub fn apply_settings<D>(
+        &mut self,
+        settings: &Settings,
+        canbus: &mut D,
+    ) -> Result<(), SLCANError>
+    where
+        D: CanDriver,
+    {
+        self.acceptance_code = settings.acceptance_code;
+        self.acceptance_mask = settings.acceptance_mask;
+        self.timestamps_enabled = settings.timestamps_enabled;
+        self.auto_poll = settings.auto_poll;
+        self.serial_number_override = settings.serial_number;
+        if let Some(bit_timing) = settings.bit_timing {
+            SLCAN::set_bit_timing(canbus, bit_timing)?;
+            self.bit_timing = Some(bit_timing);
+        }
+
+        match settings.auto_startup {
+            AutoStartup::Off => {}
+            AutoStartup::Normal => self.open_channel(canbus, false),
+            AutoStartup::ListenOnly => self.open_channel(canbus, true),
+        }
+        Ok(())
+    }
+
+    /// Takes the change to the saved settings which the host asked for, if any.
+    /// Writing it to flash is left to the caller, as it stalls the adapter.
+    pub fn take_settings_to_save(&mut self) -> Option<SettingsUpdate> {
+        self.settings_to_save.take()
+    }
+
+    /// Adds to the change to be saved, as commands may run before the previous one is.
+    fn save_settings(&mut self, update: SettingsUpdate) {
+        self.settings_to_save = Some(match self.settings_to_save {
+            Some(pending) => pending.then(update),
+            None => update,
+        });
+    }
+
+    fn settings(&self, auto_startup: AutoStartup) -> Settings {
+        Settings {
+            bit_timing: self.bit_timing,
+            acceptance_code: self.acceptance_code,
+            acceptance_mask: self.acceptance_mask,
+            timestamps_enabled: self.timestamps_enabled,
+            auto_poll: self.auto_poll,
+            auto_startup,
+            serial_number: self.serial_number_override,
         }
     }
 
+    /// Sets the bit timing selected by `S` or `s`, leaving the channel closed.
+    fn set_bit_timing<D>(canbus: &mut D, bit_timing: BitTimingSetup) -> Result<(), SLCANError>
+    where
+        D: CanDriver,
+    {
+        let result = match bit_timing {
+            BitTimingSetup::Bitrate(bitrate) => canbus.set_bitrate(bitrate),
+            BitTimingSetup::Sja1000(btr0, btr1) => {
+                let sja1000_timing = timing::from_sja1000(btr0, btr1);
+                let timing =
+                    timing::rescale(&sja1000_timing, timing::SJA1000_CLOCK_HZ, canbus.clock_hz())
+                        .ok_or(SLCANError::Regular(ErrorKind::CANError))?;
+                canbus.set_raw_bit_timing(timing.bxcan())
+            }
+        };
+        result.map_err(|_e| SLCANError::Regular(ErrorKind::CANError))
+    }
+
+    /// Opens the channel, without transmitting or acknowledging frames if listen only.
+    fn open_channel<D>(&self, canbus: &mut D, listen_only: bool)
+    where
+        D: CanDriver,
+    {
+        canbus.set_acceptance_filter(self.acceptance_code, self.acceptance_mask);
+        canbus.set_silent(listen_only);
+        canbus.enable();
+    }
+
     /// Handles a single received byte, pushing it to the rx queue.
     /// If a complete command has been received, returns it.
     pub fn handle_incoming_byte(
@@ -156,14 +373,13 @@ impl SLCAN {
         match &result {
             Err(SLCANError::Regular(kind)) => match kind {
                 ErrorKind::QueueFull => self.status.receive_queue_full = true,
-                ErrorKind::InvalidCommand => self.status.error_passive = true,
-                ErrorKind::NotImplemented => self.status.error_passive = true,
-                ErrorKind::CANError => self.status.bus_error = true,
                 ErrorKind::BufferOverrun => self.status.transmit_queue_full = true,
+                // bus conditions are reported by the CAN controller
+                ErrorKind::InvalidCommand | ErrorKind::NotImplemented | ErrorKind::CANError => {}
             },
             Ok(_c) => {}
         }
-        return result;
+        result
     }
 
     fn do_handle_incoming_byte(
@@ -173,7 +389,7 @@ impl SLCAN {
     ) -> Result<Option<Command>, SLCANError> {
         // If we received a command terminator, attempt to parse the rx queue as a single command
         if incoming_byte == COMMAND_TERMINATOR {
-            let received_bytes: RequestData = rx_queue.iter().map(|&x| x).collect();
+            let received_bytes: RequestData = rx_queue.iter().copied().collect();
             rx_queue.clear();
             let command = Command::from_bytes(&received_bytes);
             return Some(command).transpose();
@@ -181,28 +397,118 @@ impl SLCAN {
 
         // Otherwise, just push to the queue
         rx_queue.push_back(incoming_byte).map_err(err_queue_full)?;
-        return Ok(None);
+        Ok(None)
+    }
+
+    /// Reads bytes from the host until a complete command has been received, returning it.
+    /// Returns `None` once the transport has no more bytes available.
+    pub fn receive_command<T: Transport>(
+        &mut self,
+        transport: &mut T,
+        rx_queue: &mut QueueType,
+    ) -> Option<Result<Command, SLCANError>> {
+        while let Some(incoming_byte) = transport.read() {
+            match self.handle_incoming_byte(incoming_byte, rx_queue) {
+                Ok(None) => {}
+                Ok(Some(cmd)) => return Some(Ok(cmd)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+
+    /// Sends as much of the tx queue to the host as the transport accepts, then applies
+    /// a baud rate change once everything has been sent. Returns whether bytes are left to send.
+    pub fn transmit_output<T: Transport>(
+        &mut self,
+        transport: &mut T,
+        tx_queue: &mut TxQueueType,
+    ) -> bool {
+        while !tx_queue.is_empty() {
+            let written = transport.write(tx_queue.as_slices().0);
+            if written == 0 {
+                break;
+            }
+            for _ in 0..written {
+                tx_queue.pop_front();
+            }
+        }
+
+        let pending = !tx_queue.is_empty();
+        transport.set_tx_pending(pending);
+        if !pending {
+            // the response to `U` goes out at the old rate
+            if let Some(baud) = self.pending_baud_rate.take() {
+                transport.set_baud_rate(baud);
+            }
+        }
+        pending
     }
 
     /// Handles the outputs of a command, pushing to the tx queue.
+    /// The response is queued whole or not at all.
     pub fn handle_command_output(
         &mut self,
         output: &CommandReturnType,
-        tx_queue: &mut QueueType,
+        tx_queue: &mut TxQueueType,
     ) -> Result<(), SLCANError> {
+        let response_len = match output {
+            Ok(data) => data.len() + 1,
+            Err(_e) => 1,
+        };
+        // frames polled by the `A` command precede its response
+        let polled_frames = core::mem::take(&mut self.polled_frames);
+        if !self.queue_polled_frames(polled_frames, response_len, tx_queue) {
+            // not `A`, which the host takes as the FIFO being drained, so that it polls again
+            tx_queue.push_back(ERROR_CHAR).ok();
+            return Err(SLCANError::Regular(ErrorKind::QueueFull));
+        }
+
+        if tx_queue.capacity() - tx_queue.len() < response_len {
+            self.status.transmit_queue_full = true;
+            return Err(SLCANError::Regular(ErrorKind::QueueFull));
+        }
+
         let result = self
             .do_handle_command_output(output, tx_queue)
             .map_err(err_queue_full);
         if result.is_err() {
             self.status.transmit_queue_full = true;
         }
-        return result;
+        result
+    }
+
+    /// Moves up to `count` frames from the receive FIFO to the tx queue, leaving room for a
+    /// response of `response_len` bytes. Frames which do not fit are left for the next poll.
+    /// Returns whether all of them were moved.
+    fn queue_polled_frames(
+        &mut self,
+        count: usize,
+        response_len: usize,
+        tx_queue: &mut TxQueueType,
+    ) -> bool {
+        for _ in 0..count {
+            let Some((frame, timestamp)) = self.rx_fifo.front() else {
+                break;
+            };
+            let repr = SLCAN::can_frame_representation(frame, true, timestamp.as_ref());
+            if tx_queue.capacity() - tx_queue.len() < repr.len() + 1 + response_len {
+                return false;
+            }
+
+            for byte in repr {
+                tx_queue.push_back(byte).unwrap();
+            }
+            tx_queue.push_back(COMMAND_TERMINATOR).unwrap();
+            self.rx_fifo.pop_front();
+        }
+        true
     }
 
     fn do_handle_command_output(
         &mut self,
         output: &CommandReturnType,
-        tx_queue: &mut QueueType,
+        tx_queue: &mut TxQueueType,
     ) -> Result<(), u8> {
         match output {
             Ok(data) => {
@@ -218,15 +524,100 @@ impl SLCAN {
         Ok(())
     }
 
+    /// Advances the receive timestamp to `now_us` from the free-running microsecond timer.
+    /// Must be called periodically, so that the timer never wraps between updates.
+    pub fn update_timestamp(&mut self, now_us: u32) {
+        self.timestamp.update(now_us);
+    }
+
+    /// Follows the bus-off state of the open channel at `now_us`, notifying the host of
+    /// transitions and starting a delayed recovery when due. A bus-off from which the
+    /// controller recovered since the last call is reported as both transitions.
+    /// Must be called periodically.
+    pub fn update_bus_state<D>(&mut self, canbus: &mut D, now_us: u32, tx_queue: &mut TxQueueType)
+    where
+        D: CanDriver,
+    {
+        let went_bus_off = canbus.take_bus_off_event();
+        if !canbus.is_enabled() {
+            // closing the channel abandons any recovery
+            self.bus_off_since_us = None;
+            return;
+        }
+
+        let bus_off = canbus.is_bus_off();
+        match (self.bus_off_since_us, bus_off) {
+            (None, _) if bus_off || went_bus_off => {
+                self.status.bus_off = true;
+                self.notify(BUS_OFF_NOTIFICATION, tx_queue);
+                if bus_off {
+                    self.bus_off_since_us = Some(now_us);
+                } else {
+                    self.notify(BUS_ON_NOTIFICATION, tx_queue);
+                }
+            }
+            (Some(_), false) => {
+                self.bus_off_since_us = None;
+                self.notify(BUS_ON_NOTIFICATION, tx_queue);
+            }
+            (Some(since_us), true) => {
+                if let BusOffRecovery::Delayed(delay_ms) = canbus.bus_off_recovery() {
+                    if now_us.wrapping_sub(since_us) >= u32::from(delay_ms) * 1000 {
+                        // retried after the same delay until the controller is back on the bus
+                        canbus.enable();
+                        self.bus_off_since_us = Some(now_us);
+                    }
+                }
+            }
+            (None, _) => {}
+        }
+    }
+
+    /// Queues an unprompted message to the host, whole or not at all.
+    fn notify(&mut self, message: &[u8], tx_queue: &mut TxQueueType) {
+        if tx_queue.capacity() - tx_queue.len() <= message.len() {
+            self.status.transmit_queue_full = true;
+            return;
+        }
+        for byte in message {
+            tx_queue.push_back(*byte).unwrap();
+        }
+        tx_queue.push_back(COMMAND_TERMINATOR).unwrap();
+    }
+
+    /// Handles a frame received from the CAN bus at `now_us`, pushing it to the tx queue,
+    /// or to the receive FIFO while auto-poll is off.
+    /// The frame is queued whole or not at all.
     pub fn handle_incoming_can_frame(
+        &mut self,
         frame: &bxcan::Frame,
-        tx_queue: &mut QueueType,
+        now_us: u32,
+        tx_queue: &mut TxQueueType,
     ) -> Result<(), SLCANError> {
-        let repr = SLCAN::can_frame_representation(frame, true);
+        self.timestamp.update(now_us);
+        let timestamp = if self.timestamps_enabled {
+            Some(self.timestamp.clone())
+        } else {
+            None
+        };
+
+        if !self.auto_poll {
+            // held until the host polls for it
+            return self
+                .rx_fifo
+                .push_back((frame.clone(), timestamp))
+                .map_err(|_| {
+                    self.status.receive_queue_full = true;
+                    SLCANError::Regular(ErrorKind::BufferOverrun)
+                });
+        }
+
+        let repr = SLCAN::can_frame_representation(frame, true, timestamp.as_ref());
 
         let available = tx_queue.capacity() - tx_queue.len();
         // need 1 extra space for terminator
         if repr.len() >= available {
+            self.status.receive_queue_full = true;
             return Err(SLCANError::Regular(ErrorKind::BufferOverrun));
         }
 
@@ -241,13 +632,16 @@ impl SLCAN {
     fn can_frame_representation(
         frame: &bxcan::Frame,
         include_start_byte: bool,
-    ) -> heapless::Vec<u8, 24> {
-        let mut rep = heapless::Vec::<u8, 24>::new();
+        timestamp: Option<&Timestamp>,
+    ) -> heapless::Vec<u8, 32> {
+        let mut rep = heapless::Vec::<u8, 32>::new();
 
         if include_start_byte {
-            let start_byte = match frame.id() {
-                bxcan::Id::Standard(_id) => b"t",
-                bxcan::Id::Extended(_id) => b"T",
+            let start_byte = match (frame.id(), frame.is_remote_frame()) {
+                (bxcan::Id::Standard(_id), false) => b"t",
+                (bxcan::Id::Extended(_id), false) => b"T",
+                (bxcan::Id::Standard(_id), true) => b"r",
+                (bxcan::Id::Extended(_id), true) => b"R",
             };
             rep.extend_from_slice(start_byte).unwrap();
         }
@@ -275,23 +669,28 @@ impl SLCAN {
                     data_str.extend_from_slice(&hex_str).unwrap();
                 }
                 rep.extend(data_str);
-
-                // TODO send timestamps
             }
             None => {
-                let data_len = b"0";
-                rep.extend_from_slice(data_len).unwrap();
+                // remote frames carry a data length code but no data
+                let data_len: u8 = char::from_digit(frame.dlc() as u32, 10).unwrap() as u8;
+                rep.extend_from_slice(&[data_len]).unwrap();
             }
         }
 
-        return rep;
+        if let Some(timestamp) = timestamp {
+            rep.extend_from_slice(&timestamp.as_hex()).unwrap();
+        }
+
+        rep
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum CommandVariant {
     Setup,
     SetupWithBTR,
     OpenChannel,
+    OpenChannelListenOnly,
     CloseChannel,
     TransmitFrame,
     TransmitExtendedFrame,
@@ -301,8 +700,19 @@ enum CommandVariant {
     SetAcceptanceCode,
     SetAcceptanceMask,
     GetVersion,
+    GetBuildInfo,
     GetSerialNumber,
+    SetSerialNumber,
     EnableTimeStamps,
+    SetTestMode,
+    SetBusOffRecovery,
+    SetAutoPoll,
+    SetUartBaudRate,
+    SetAutoStartup,
+    PollOne,
+    PollAll,
+    GetErrorState,
+    GetResetCause,
 }
 
 /// Data container for an SLCAN command
@@ -312,7 +722,8 @@ pub struct Command {
 }
 
 type RequestData = heapless::Vec<u8, 32>;
-pub type ResponseData = heapless::Vec<u8, 32>;
+/// Long enough for the build description returned by `v`.
+pub type ResponseData = heapless::Vec<u8, 128>;
 pub type CommandReturnType = Result<ResponseData, SLCANError>;
 
 impl Command {
@@ -323,6 +734,7 @@ impl Command {
             Some(b'S') => CommandVariant::Setup,
             Some(b's') => CommandVariant::SetupWithBTR,
             Some(b'O') => CommandVariant::OpenChannel,
+            Some(b'L') => CommandVariant::OpenChannelListenOnly,
             Some(b'C') => CommandVariant::CloseChannel,
             Some(b't') => CommandVariant::TransmitFrame,
             Some(b'T') => CommandVariant::TransmitExtendedFrame,
@@ -332,53 +744,68 @@ impl Command {
             Some(b'M') => CommandVariant::SetAcceptanceCode,
             Some(b'm') => CommandVariant::SetAcceptanceMask,
             Some(b'V') => CommandVariant::GetVersion,
+            Some(b'v') => CommandVariant::GetBuildInfo,
             Some(b'N') => CommandVariant::GetSerialNumber,
+            Some(b'n') => CommandVariant::SetSerialNumber,
             Some(b'Z') => CommandVariant::EnableTimeStamps,
+            Some(b'X') => CommandVariant::SetAutoPoll,
+            Some(b'U') => CommandVariant::SetUartBaudRate,
+            Some(b'Q') => CommandVariant::SetAutoStartup,
+            Some(b'P') => CommandVariant::PollOne,
+            Some(b'A') => CommandVariant::PollAll,
+            Some(b'l') => CommandVariant::SetTestMode,
+            Some(b'b') => CommandVariant::SetBusOffRecovery,
+            Some(b'E') => CommandVariant::GetErrorState,
+            Some(b'?') => CommandVariant::GetResetCause,
             _ => return Err(SLCANError::Regular(ErrorKind::InvalidCommand)),
         };
         let data = heapless::Vec::from_slice(&bytes[1..])
             .map_err(|_e| SLCANError::Regular(ErrorKind::InvalidCommand))?;
 
-        return Ok(Command {
-            variant: variant,
-            data: data,
-        });
+        Ok(Command { variant, data })
     }
 
     /// Runs the command, returning any bytes to be sent back over serial.
-    pub fn run<I>(&self, slcan: &mut SLCAN, canbus: &mut CANBus<I>) -> CommandReturnType
+    pub fn run<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
     where
-        I: bxcan::FilterOwner,
+        D: CanDriver,
     {
         match self.variant {
             CommandVariant::Setup => self.run_setup(slcan, canbus),
-            CommandVariant::SetupWithBTR => self.run_not_implemented(slcan),
-            CommandVariant::OpenChannel => self.run_open_channel(slcan, canbus),
+            CommandVariant::SetupWithBTR => self.run_setup_with_btr(slcan, canbus),
+            CommandVariant::OpenChannel => self.run_open_channel(slcan, canbus, false),
+            CommandVariant::OpenChannelListenOnly => self.run_open_channel(slcan, canbus, true),
             CommandVariant::CloseChannel => self.run_close_channel(slcan, canbus),
-            CommandVariant::TransmitFrame => self.run_transmit_frame(slcan, canbus),
-            CommandVariant::TransmitExtendedFrame => self.run_transmit_extended_frame(slcan, canbus),
-            CommandVariant::TransmitRTRFrame => self.run_not_implemented(slcan),
-            CommandVariant::TransmitExtendedRTRFrame => self.run_not_implemented(slcan),
-            CommandVariant::ReadStatusFlags => self.run_read_status_flags(slcan),
-            CommandVariant::SetAcceptanceCode => self.run_not_implemented(slcan),
-            CommandVariant::SetAcceptanceMask => self.run_not_implemented(slcan),
+            CommandVariant::TransmitFrame
+            | CommandVariant::TransmitExtendedFrame
+            | CommandVariant::TransmitRTRFrame
+            | CommandVariant::TransmitExtendedRTRFrame => self.run_transmit_frame(slcan, canbus),
+            CommandVariant::ReadStatusFlags => self.run_read_status_flags(slcan, canbus),
+            CommandVariant::SetAcceptanceCode => self.run_set_acceptance_code(slcan, canbus),
+            CommandVariant::SetAcceptanceMask => self.run_set_acceptance_mask(slcan, canbus),
             CommandVariant::GetVersion => self.run_get_version(slcan),
+            CommandVariant::GetBuildInfo => self.run_get_build_info(slcan),
             CommandVariant::GetSerialNumber => self.run_get_serial_number(slcan),
+            CommandVariant::SetSerialNumber => self.run_set_serial_number(slcan),
             CommandVariant::EnableTimeStamps => self.run_enable_timestamps(slcan),
+            CommandVariant::SetAutoPoll => self.run_set_auto_poll(slcan, canbus),
+            CommandVariant::SetUartBaudRate => self.run_set_uart_baud_rate(slcan, canbus),
+            CommandVariant::SetAutoStartup => self.run_set_auto_startup(slcan, canbus),
+            CommandVariant::PollOne => self.run_poll_one(slcan, canbus),
+            CommandVariant::PollAll => self.run_poll_all(slcan, canbus),
+            CommandVariant::SetTestMode => self.run_set_test_mode(slcan, canbus),
+            CommandVariant::SetBusOffRecovery => self.run_set_bus_off_recovery(slcan, canbus),
+            CommandVariant::GetErrorState => self.run_get_error_state(canbus),
+            CommandVariant::GetResetCause => self.run_get_reset_cause(slcan),
         }
     }
 
-    /// Placeholder command for unimplemented commands
-    fn run_not_implemented(&self, _slcan: &mut SLCAN) -> CommandReturnType {
-        Err(SLCANError::Regular(ErrorKind::NotImplemented))
-    }
-
-    fn run_setup<I>(&self, slcan: &mut SLCAN, canbus: &mut CANBus<I>) -> CommandReturnType
+    fn run_setup<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
     where
-        I: bxcan::FilterOwner,
+        D: CanDriver,
     {
         // set CAN bitrate
-        let bitrate = match self.data.get(0) {
+        let bitrate = match self.data.first() {
             Some(b'0') => CANBitrate::Bitrate10k,
             Some(b'1') => CANBitrate::Bitrate20k,
             Some(b'2') => CANBitrate::Bitrate50k,
@@ -390,78 +817,115 @@ impl Command {
             Some(b'8') => CANBitrate::Bitrate1M,
             _ => return Err(SLCANError::Regular(ErrorKind::InvalidCommand)),
         };
-        canbus
-            .set_bitrate(bitrate)
-            .map_err(|_e| SLCANError::Regular(ErrorKind::CANError))?;
-        slcan.bitrate = Some(bitrate);
+        let bit_timing = BitTimingSetup::Bitrate(bitrate);
+        SLCAN::set_bit_timing(canbus, bit_timing)?;
+        slcan.bit_timing = Some(bit_timing);
         Ok(ResponseData::new())
     }
 
-    fn run_open_channel<I>(&self, _slcan: &mut SLCAN, canbus: &mut CANBus<I>) -> CommandReturnType
+    fn run_setup_with_btr<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
     where
-        I: bxcan::FilterOwner,
+        D: CanDriver,
     {
-        // open the CAN channel
-        canbus.enable();
+        // set CAN bit timing from SJA1000 BTR0/BTR1 registers
+        if self.data.len() != 4 {
+            return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
+        }
+        let mut btr = [0u8; 2];
+        hex::decode_to_slice(&self.data[..], &mut btr).map_err(err_invalid_command)?;
+
+        let bit_timing = BitTimingSetup::Sja1000(btr[0], btr[1]);
+        SLCAN::set_bit_timing(canbus, bit_timing)?;
+        slcan.bit_timing = Some(bit_timing);
+        Ok(ResponseData::new())
+    }
+
+    fn run_open_channel<D>(
+        &self,
+        slcan: &mut SLCAN,
+        canbus: &mut D,
+        listen_only: bool,
+    ) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // open the CAN channel, without transmitting or acknowledging frames if listen only
+        slcan.open_channel(canbus, listen_only);
         Ok(ResponseData::new())
     }
 
-    fn run_close_channel<I>(&self, _slcan: &mut SLCAN, canbus: &mut CANBus<I>) -> CommandReturnType
+    fn run_close_channel<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
     where
-        I: bxcan::FilterOwner,
+        D: CanDriver,
     {
-        // close the CAN channel
+        // close the CAN channel, discarding frames not yet polled as they belong to its setup
         canbus.disable();
+        slcan.rx_fifo.clear();
+        slcan.polled_frames = 0;
         Ok(ResponseData::new())
     }
 
-    fn run_transmit_frame<I>(&self, _slcan: &mut SLCAN, canbus: &mut CANBus<I>) -> CommandReturnType
+    fn run_transmit_frame<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
     where
-        I: bxcan::FilterOwner,
+        D: CanDriver,
     {
-        // transmit a frame
-        // frame must have minimum 4 bytes
-        if self.data.len() < 4 {
+        // transmit a data or remote frame
+        let frame = self.decode_frame()?;
+        if canbus.is_silent() {
             return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
         }
 
-        let mut id = [0u8; 2];
-        let padded_slice: [u8; 4] = pad_left(&self.data[0..3]).unwrap();
+        let dequeued_frame = canbus.transmit(&frame).map_err(|_e| {
+            slcan.status.transmit_queue_full = true;
+            SLCANError::Regular(ErrorKind::QueueFull)
+        })?;
+        if dequeued_frame.is_some() {
+            // a lower priority pending frame was dropped to make room for this one
+            slcan.status.transmit_queue_full = true;
+        }
 
-        hex::decode_to_slice(&padded_slice, &mut id).map_err(err_invalid_command)?;
-        let id = bxcan::StandardId::new(u16::from_be_bytes(id))
-            .ok_or(SLCANError::Regular(ErrorKind::InvalidCommand))?;
+        // acknowledge with z or Z when frames are returned without polling
+        let mut response = ResponseData::new();
+        if slcan.auto_poll {
+            let ack = match self.variant {
+                CommandVariant::TransmitFrame | CommandVariant::TransmitRTRFrame => b'z',
+                _ => b'Z',
+            };
+            response.push(ack).unwrap();
+        }
+        Ok(response)
+    }
 
-        let mut data_len = [0u8; 1];
-        let padded_slice: [u8; 2] = pad_left(&self.data[3..4]).unwrap();
-        hex::decode_to_slice(padded_slice, &mut data_len).map_err(err_invalid_command)?;
-        let data_len: usize = u8::from_be_bytes(data_len).into();
+    /// Decodes the frame carried by one of the transmit commands.
+    fn decode_frame(&self) -> Result<bxcan::Frame, SLCANError> {
+        match self.variant {
+            CommandVariant::TransmitFrame => self.decode_standard_frame(false),
+            CommandVariant::TransmitExtendedFrame => self.decode_extended_frame(false),
+            CommandVariant::TransmitRTRFrame => self.decode_standard_frame(true),
+            CommandVariant::TransmitExtendedRTRFrame => self.decode_extended_frame(true),
+            _ => Err(SLCANError::Regular(ErrorKind::InvalidCommand)),
+        }
+    }
 
-        // ensure frame size is correct
-        let expected_len: usize = (4 + 2 * data_len).into();
-        if data_len > 8 || self.data.len() != expected_len {
+    /// Decodes a frame with a standard identifier (`tiiil...` or `riiil`).
+    fn decode_standard_frame(&self, remote: bool) -> Result<bxcan::Frame, SLCANError> {
+        // frame must have minimum 4 bytes
+        if self.data.len() < 4 {
             return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
         }
 
-        let mut data = [0u8; 8];
-        let padded_slice: [u8; 16] = pad_left(&self.data[4..]).unwrap();
-        hex::decode_to_slice(padded_slice, &mut data).map_err(err_invalid_command)?;
+        let mut id = [0u8; 2];
+        let padded_slice: [u8; 4] = pad_left(&self.data[0..3]).unwrap();
 
-        let frame = bxcan::Frame::new_data(id, bxcan::Data::new(&data[8 - data_len..]).unwrap());
+        hex::decode_to_slice(padded_slice, &mut id).map_err(err_invalid_command)?;
+        let id = bxcan::StandardId::new(u16::from_be_bytes(id))
+            .ok_or(SLCANError::Regular(ErrorKind::InvalidCommand))?;
 
-        canbus.transmit(&frame).unwrap();
-        Ok(ResponseData::new())
+        self.decode_frame_body(id.into(), 3, remote)
     }
 
-    fn run_transmit_extended_frame<I>(
-        &self,
-        _slcan: &mut SLCAN,
-        canbus: &mut CANBus<I>,
-    ) -> CommandReturnType
-    where
-        I: bxcan::FilterOwner,
-    {
-        // transmit an extended frame
+    /// Decodes a frame with an extended identifier (`Tiiiiiiiil...` or `Riiiiiiiil`).
+    fn decode_extended_frame(&self, remote: bool) -> Result<bxcan::Frame, SLCANError> {
         // frame must have minimum 9 bytes
         if self.data.len() < 9 {
             return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
@@ -471,54 +935,1317 @@ impl Command {
         let id = bxcan::ExtendedId::new(u32::from_be_bytes(id))
             .ok_or(SLCANError::Regular(ErrorKind::InvalidCommand))?;
 
+        self.decode_frame_body(id.into(), 8, remote)
+    }
+
+    /// Decodes the data length code at `dlc_idx` and any data bytes following it.
+    /// Remote frames carry a data length code but no data.
+    fn decode_frame_body(
+        &self,
+        id: bxcan::Id,
+        dlc_idx: usize,
+        remote: bool,
+    ) -> Result<bxcan::Frame, SLCANError> {
         let mut data_len = [0u8; 1];
-        let padded_slice: [u8; 2] = pad_left(&self.data[8..9]).unwrap();
+        let padded_slice: [u8; 2] = pad_left(&self.data[dlc_idx..dlc_idx + 1]).unwrap();
         hex::decode_to_slice(padded_slice, &mut data_len).map_err(err_invalid_command)?;
         let data_len: usize = u8::from_be_bytes(data_len).into();
 
         // ensure frame size is correct
-        let expected_len: usize = (9 + 2 * data_len).into();
+        let expected_len: usize = if remote {
+            dlc_idx + 1
+        } else {
+            dlc_idx + 1 + 2 * data_len
+        };
         if data_len > 8 || self.data.len() != expected_len {
             return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
         }
 
+        if remote {
+            return Ok(bxcan::Frame::new_remote(id, data_len as u8));
+        }
+
         let mut data = [0u8; 8];
-        let padded_slice: [u8; 16] = pad_left(&self.data[9..]).unwrap();
+        let padded_slice: [u8; 16] = pad_left(&self.data[dlc_idx + 1..]).unwrap();
         hex::decode_to_slice(padded_slice, &mut data).map_err(err_invalid_command)?;
 
-        let frame = bxcan::Frame::new_data(id, bxcan::Data::new(&data[8 - data_len..]).unwrap());
+        Ok(bxcan::Frame::new_data(
+            id,
+            bxcan::Data::new(&data[8 - data_len..]).unwrap(),
+        ))
+    }
+
+    fn run_set_acceptance_code<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // set the acceptance code, applied when the channel is next opened
+        if canbus.is_enabled() {
+            return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
+        }
+        slcan.acceptance_code = self.decode_acceptance_register()?;
+        Ok(ResponseData::new())
+    }
 
-        canbus.transmit(&frame).unwrap();
+    fn run_set_acceptance_mask<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // set the acceptance mask, applied when the channel is next opened
+        if canbus.is_enabled() {
+            return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
+        }
+        slcan.acceptance_mask = self.decode_acceptance_register()?;
         Ok(ResponseData::new())
     }
 
-    fn run_read_status_flags(&self, slcan: &mut SLCAN) -> CommandReturnType {
-        // return status flags
-        Ok(ResponseData::from_slice(&concat(b"F", &slcan.status.as_hex())).unwrap())
+    /// Decodes the 8 hex digit SJA1000 register value given to the `M` and `m` commands.
+    fn decode_acceptance_register(&self) -> Result<u32, SLCANError> {
+        if self.data.len() != 8 {
+            return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
+        }
+        let mut value = [0u8; 4];
+        hex::decode_to_slice(&self.data[..], &mut value).map_err(err_invalid_command)?;
+        Ok(u32::from_be_bytes(value))
+    }
+
+    fn run_read_status_flags<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // return status flags, clearing them once read
+        slcan.status.update(&canbus.status());
+        let response = ResponseData::from_slice(&concat(b"F", &slcan.status.as_hex())).unwrap();
+        slcan.status = StatusFlags::new();
+        Ok(response)
     }
 
     fn run_get_version(&self, slcan: &mut SLCAN) -> CommandReturnType {
         // return version
-        Ok(ResponseData::from_slice(&concat(b"V", &slcan.version.as_hex())).unwrap())
+        Ok(ResponseData::from_slice(&concat(b"V", &slcan.build_info.as_hex())).unwrap())
+    }
+
+    fn run_get_build_info(&self, slcan: &mut SLCAN) -> CommandReturnType {
+        // extension: describe the exact firmware, for support requests
+        let info = &slcan.build_info;
+        let mut response = ResponseData::new();
+        write!(
+            response,
+            "vversion={} board={} git={} date={} features={}",
+            info.version, info.board, info.git_hash, info.build_date, info.features
+        )
+        .map_err(|_e| SLCANError::Regular(ErrorKind::InvalidCommand))?;
+        Ok(response)
     }
 
     fn run_get_serial_number(&self, slcan: &mut SLCAN) -> CommandReturnType {
         // return serial number
-        Ok(ResponseData::from_slice(&concat(b"N", &slcan.serial_number)).unwrap())
+        Ok(ResponseData::from_slice(&concat(b"N", &slcan.serial_number())).unwrap())
+    }
+
+    fn run_set_serial_number(&self, slcan: &mut SLCAN) -> CommandReturnType {
+        // save a serial number in place of the derived one, or go back to it if none is given
+        slcan.serial_number_override = match &self.data[..] {
+            [] => None,
+            data if is_valid_serial_number(data) => Some(data.try_into().unwrap()),
+            _ => return Err(SLCANError::Regular(ErrorKind::InvalidCommand)),
+        };
+        // the other saved settings are kept, rather than replaced by the current ones
+        slcan.save_settings(SettingsUpdate::SerialNumber(slcan.serial_number_override));
+        Ok(ResponseData::new())
     }
 
     fn run_enable_timestamps(&self, slcan: &mut SLCAN) -> CommandReturnType {
         // set timestamps on or off
-        match self.data.get(0) {
+        match self.data.first() {
             Some(b'0') => {
                 slcan.timestamps_enabled = false;
-                return Ok(ResponseData::new());
+                Ok(ResponseData::new())
             }
             Some(b'1') => {
                 slcan.timestamps_enabled = true;
-                return Ok(ResponseData::new());
+                Ok(ResponseData::new())
             }
+            _ => Err(SLCANError::Regular(ErrorKind::InvalidCommand)),
+        }
+    }
+
+    fn run_set_auto_poll<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // send received frames as they arrive, or hold them until polled
+        if canbus.is_enabled() {
+            return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
+        }
+        slcan.auto_poll = match &self.data[..] {
+            b"0" => false,
+            b"1" => true,
             _ => return Err(SLCANError::Regular(ErrorKind::InvalidCommand)),
+        };
+        slcan.rx_fifo.clear();
+        Ok(ResponseData::new())
+    }
+
+    fn run_set_uart_baud_rate<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // change the host baud rate, after acknowledging at the current rate
+        if canbus.is_enabled() {
+            return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
         }
+        let baud = match &self.data[..] {
+            [digit] => char::from(*digit)
+                .to_digit(16)
+                .and_then(|index| UART_BAUD_RATES.get(index as usize)),
+            _ => None,
+        };
+        slcan.pending_baud_rate =
+            Some(*baud.ok_or(SLCANError::Regular(ErrorKind::InvalidCommand))?);
+        Ok(ResponseData::new())
+    }
+
+    fn run_set_auto_startup<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // save the current settings, opening the channel at power-on as it is now opened
+        let auto_startup = match &self.data[..] {
+            b"0" => AutoStartup::Off,
+            b"1" => AutoStartup::Normal,
+            b"2" => AutoStartup::ListenOnly,
+            _ => return Err(SLCANError::Regular(ErrorKind::InvalidCommand)),
+        };
+        if auto_startup != AutoStartup::Off && !canbus.is_enabled() {
+            return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
+        }
+        slcan.save_settings(SettingsUpdate::All(slcan.settings(auto_startup)));
+        Ok(ResponseData::new())
+    }
+
+    /// Checks that received frames are being held for polling.
+    fn check_pollable<D>(slcan: &SLCAN, canbus: &D) -> Result<(), SLCANError>
+    where
+        D: CanDriver,
+    {
+        if slcan.auto_poll || !canbus.is_enabled() {
+            return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
+        }
+        Ok(())
+    }
+
+    fn run_poll_one<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // return the oldest received frame, or an empty response if there is none
+        Command::check_pollable(slcan, canbus)?;
+        let mut response = ResponseData::new();
+        if let Some((frame, timestamp)) = slcan.rx_fifo.pop_front() {
+            let repr = SLCAN::can_frame_representation(&frame, true, timestamp.as_ref());
+            response.extend_from_slice(&repr).unwrap();
+        }
+        Ok(response)
+    }
+
+    fn run_poll_all<D>(&self, slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // return all received frames followed by A, the frames are sent with the output
+        Command::check_pollable(slcan, canbus)?;
+        slcan.polled_frames = slcan.rx_fifo.len();
+        Ok(ResponseData::from_slice(b"A").unwrap())
+    }
+
+    fn run_set_test_mode<D>(&self, _slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // extension: select a loopback test mode, only while the channel is closed
+        if canbus.is_enabled() {
+            return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
+        }
+        let mode = match &self.data[..] {
+            b"0" => TestMode::Normal,
+            b"1" => TestMode::Loopback,
+            b"2" => TestMode::SilentLoopback,
+            _ => return Err(SLCANError::Regular(ErrorKind::InvalidCommand)),
+        };
+        canbus.set_test_mode(mode);
+        Ok(ResponseData::new())
+    }
+
+    fn run_set_bus_off_recovery<D>(&self, _slcan: &mut SLCAN, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // extension: select how to recover from bus-off, only while the channel is closed
+        if canbus.is_enabled() {
+            return Err(SLCANError::Regular(ErrorKind::InvalidCommand));
+        }
+        let recovery = match &self.data[..] {
+            b"0" => BusOffRecovery::Automatic,
+            b"1" => BusOffRecovery::Manual,
+            [b'2', delay @ ..] if delay.len() == 4 => {
+                let mut delay_ms = [0u8; 2];
+                hex::decode_to_slice(delay, &mut delay_ms).map_err(err_invalid_command)?;
+                BusOffRecovery::Delayed(u16::from_be_bytes(delay_ms))
+            }
+            _ => return Err(SLCANError::Regular(ErrorKind::InvalidCommand)),
+        };
+        canbus.set_bus_off_recovery(recovery);
+        Ok(ResponseData::new())
+    }
+
+    fn run_get_error_state<D>(&self, canbus: &mut D) -> CommandReturnType
+    where
+        D: CanDriver,
+    {
+        // extension: return the transmit and receive error counters and the last error code
+        let error_state = canbus.error_state();
+        Ok(ResponseData::from_slice(&concat(b"E", &error_state.as_hex())).unwrap())
+    }
+
+    fn run_get_reset_cause(&self, slcan: &mut SLCAN) -> CommandReturnType {
+        // extension: return why the adapter last started, and where it panicked if it did
+        let mut response = ResponseData::new();
+        response.push(b'?').unwrap();
+        match &slcan.reset_info.panic {
+            Some(location) => {
+                write!(response, "panic {}:{}", location.file, location.line).unwrap();
+            }
+            None => response
+                .extend_from_slice(slcan.reset_info.cause.name().as_bytes())
+                .unwrap(),
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canbus::mock::MockCanDriver;
+    use crate::canbus::LastErrorCode;
+    use crate::config::mock::MockFlash;
+    use crate::config::ConfigStore;
+    use crate::reset::PanicRecord;
+    use crate::transport::MemoryTransport;
+
+    const CLOCK_HZ: u32 = 36_000_000;
+
+    fn command(bytes: &[u8]) -> Command {
+        Command::from_bytes(&RequestData::from_slice(bytes).unwrap()).unwrap()
+    }
+
+    fn run(bytes: &[u8], slcan: &mut SLCAN, can: &mut MockCanDriver) -> CommandReturnType {
+        command(bytes).run(slcan, can)
+    }
+
+    fn standard_id(id: u16) -> StandardId {
+        StandardId::new(id).unwrap()
+    }
+
+    fn extended_id(id: u32) -> ExtendedId {
+        ExtendedId::new(id).unwrap()
+    }
+
+    #[test]
+    fn parses_command_variants() {
+        for (bytes, variant) in [
+            (&b"S6"[..], CommandVariant::Setup),
+            (b"s031C", CommandVariant::SetupWithBTR),
+            (b"O", CommandVariant::OpenChannel),
+            (b"L", CommandVariant::OpenChannelListenOnly),
+            (b"C", CommandVariant::CloseChannel),
+            (b"t1230", CommandVariant::TransmitFrame),
+            (b"T123456780", CommandVariant::TransmitExtendedFrame),
+            (b"r1230", CommandVariant::TransmitRTRFrame),
+            (b"R123456780", CommandVariant::TransmitExtendedRTRFrame),
+            (b"F", CommandVariant::ReadStatusFlags),
+            (b"M00000000", CommandVariant::SetAcceptanceCode),
+            (b"mFFFFFFFF", CommandVariant::SetAcceptanceMask),
+            (b"V", CommandVariant::GetVersion),
+            (b"v", CommandVariant::GetBuildInfo),
+            (b"N", CommandVariant::GetSerialNumber),
+            (b"nCAN1", CommandVariant::SetSerialNumber),
+            (b"Z1", CommandVariant::EnableTimeStamps),
+            (b"X0", CommandVariant::SetAutoPoll),
+            (b"U1", CommandVariant::SetUartBaudRate),
+            (b"Q1", CommandVariant::SetAutoStartup),
+            (b"P", CommandVariant::PollOne),
+            (b"A", CommandVariant::PollAll),
+            (b"l2", CommandVariant::SetTestMode),
+            (b"b1", CommandVariant::SetBusOffRecovery),
+            (b"E", CommandVariant::GetErrorState),
+            (b"?", CommandVariant::GetResetCause),
+        ] {
+            let cmd = command(bytes);
+            assert_eq!(cmd.variant, variant);
+            assert_eq!(cmd.data, bytes[1..]);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        for bytes in [&b""[..], b"x", b"1", b"\x07"] {
+            let bytes = RequestData::from_slice(bytes).unwrap();
+            assert!(matches!(
+                Command::from_bytes(&bytes),
+                Err(SLCANError::Regular(ErrorKind::InvalidCommand))
+            ));
+        }
+    }
+
+    #[test]
+    fn runs_version_and_serial_number_queries() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        assert_eq!(run(b"V", &mut slcan, &mut can).unwrap(), b"V0000"[..]);
+        assert_eq!(run(b"N", &mut slcan, &mut can).unwrap(), b"NF446"[..]);
+    }
+
+    #[test]
+    fn describes_build() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        slcan.set_build_info(BuildInfo {
+            hardware_version: 0x10,
+            software_version: 0x12,
+            version: "1.2.3",
+            board: "nucleo-f446ze",
+            git_hash: "0123456789ab-dirty",
+            build_date: "2026-10-16",
+            features: "usb",
+        });
+        assert_eq!(run(b"V", &mut slcan, &mut can).unwrap(), b"V1012"[..]);
+        assert_eq!(
+            run(b"v", &mut slcan, &mut can).unwrap(),
+            b"vversion=1.2.3 board=nucleo-f446ze git=0123456789ab-dirty date=2026-10-16 \
+              features=usb"[..]
+        );
+    }
+
+    #[test]
+    fn reports_reset_cause() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        assert_eq!(run(b"?", &mut slcan, &mut can).unwrap(), b"?unknown"[..]);
+
+        slcan.set_reset_info(ResetInfo::new(ResetCause::PowerOn, None));
+        assert_eq!(run(b"?", &mut slcan, &mut can).unwrap(), b"?power-on"[..]);
+
+        let location = PanicRecord::new("src/slcan.rs", 123).location();
+        slcan.set_reset_info(ResetInfo::new(ResetCause::Software, location));
+        assert_eq!(
+            run(b"?", &mut slcan, &mut can).unwrap(),
+            b"?panic src/slcan.rs:123"[..]
+        );
+    }
+
+    #[test]
+    fn runs_timestamp_toggle() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        assert!(run(b"Z1", &mut slcan, &mut can).is_ok());
+        assert!(slcan.timestamps_enabled);
+        assert!(run(b"Z0", &mut slcan, &mut can).is_ok());
+        assert!(!slcan.timestamps_enabled);
+
+        for bytes in [&b"Z"[..], b"Z2", b"Zx"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_err());
+        }
+    }
+
+    #[test]
+    fn runs_setup_and_open() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        assert!(run(b"S6", &mut slcan, &mut can).is_ok());
+        let expected = timing::calculate(CLOCK_HZ, 500_000).unwrap().bxcan();
+        assert_eq!(can.bit_timing, Some(expected));
+        assert!(run(b"S9", &mut slcan, &mut can).is_err());
+
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        assert!(can.is_enabled());
+        assert_eq!(can.acceptance_filter, Some((0x0000_0000, 0xFFFF_FFFF)));
+
+        assert!(run(b"C", &mut slcan, &mut can).is_ok());
+        assert!(!can.is_enabled());
+    }
+
+    #[test]
+    fn runs_setup_with_btr() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        // 500 kbit/s at 87.5%, rescaled to the peripheral clock
+        assert!(run(b"s001C", &mut slcan, &mut can).is_ok());
+        let expected = timing::rescale(
+            &timing::from_sja1000(0x00, 0x1C),
+            timing::SJA1000_CLOCK_HZ,
+            CLOCK_HZ,
+        );
+        assert_eq!(can.bit_timing, expected.map(|timing| timing.bxcan()));
+
+        for bytes in [&b"s"[..], b"s001", b"s001C0", b"s0X1C"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_err());
+        }
+    }
+
+    #[test]
+    fn saves_settings_for_auto_startup() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        // starting automatically needs the channel open, so that its setup is known to work
+        assert!(run(b"Q1", &mut slcan, &mut can).is_err());
+        for bytes in [&b"Q"[..], b"Q3", b"Q10"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_err());
+        }
+        assert_eq!(slcan.take_settings_to_save(), None);
+
+        assert!(run(b"Q0", &mut slcan, &mut can).is_ok());
+        assert_eq!(
+            slcan.take_settings_to_save(),
+            Some(SettingsUpdate::All(Settings::default()))
+        );
+
+        for bytes in [
+            &b"s001C"[..],
+            b"M12345678",
+            b"m0000FFFF",
+            b"Z1",
+            b"L",
+            b"Q2",
+        ] {
+            assert!(run(bytes, &mut slcan, &mut can).is_ok());
+        }
+        assert_eq!(
+            slcan.take_settings_to_save(),
+            Some(SettingsUpdate::All(Settings {
+                bit_timing: Some(BitTimingSetup::Sja1000(0x00, 0x1C)),
+                acceptance_code: 0x1234_5678,
+                acceptance_mask: 0x0000_FFFF,
+                timestamps_enabled: true,
+                auto_poll: true,
+                auto_startup: AutoStartup::ListenOnly,
+                serial_number: None,
+            }))
+        );
+        assert_eq!(slcan.take_settings_to_save(), None);
+    }
+
+    #[test]
+    fn saves_serial_number_override() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        slcan.set_serial_number(*b"7QX2");
+        assert_eq!(run(b"N", &mut slcan, &mut can).unwrap(), b"N7QX2"[..]);
+
+        for bytes in [&b"nCAN"[..], b"nCAN12", b"ncan1", b"nCA 1"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_err());
+        }
+        assert_eq!(slcan.take_settings_to_save(), None);
+
+        assert!(run(b"nCAN1", &mut slcan, &mut can).is_ok());
+        assert_eq!(run(b"N", &mut slcan, &mut can).unwrap(), b"NCAN1"[..]);
+        assert_eq!(
+            slcan.take_settings_to_save(),
+            Some(SettingsUpdate::SerialNumber(Some(*b"CAN1")))
+        );
+
+        assert!(run(b"n", &mut slcan, &mut can).is_ok());
+        assert_eq!(run(b"N", &mut slcan, &mut can).unwrap(), b"N7QX2"[..]);
+        assert_eq!(
+            slcan.take_settings_to_save(),
+            Some(SettingsUpdate::SerialNumber(None))
+        );
+    }
+
+    #[test]
+    fn merges_settings_saved_by_consecutive_commands() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        // both commands run before the settings are written
+        for bytes in [&b"O"[..], b"Q1", b"nCAN1"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_ok());
+        }
+        assert_eq!(
+            slcan.take_settings_to_save(),
+            Some(SettingsUpdate::All(Settings {
+                auto_startup: AutoStartup::Normal,
+                serial_number: Some(*b"CAN1"),
+                ..Settings::default()
+            }))
+        );
+
+        for bytes in [&b"nCAN1"[..], b"n", b"Q0"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_ok());
+        }
+        assert_eq!(
+            slcan.take_settings_to_save(),
+            Some(SettingsUpdate::All(Settings::default()))
+        );
+    }
+
+    #[test]
+    fn saving_serial_number_keeps_saved_settings() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut config = ConfigStore::new(MockFlash::<256>::new());
+        let mut save = |slcan: &mut SLCAN| {
+            config
+                .update(&slcan.take_settings_to_save().unwrap())
+                .unwrap();
+            config.load().unwrap()
+        };
+
+        for bytes in [&b"S6"[..], b"Z1", b"O", b"Q1"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_ok());
+        }
+        let saved = save(&mut slcan);
+
+        // settings being tried out are not saved along with the serial number
+        for bytes in [&b"C"[..], b"S3", b"Z0", b"m0000FFFF"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_ok());
+        }
+        assert!(run(b"nCAN1", &mut slcan, &mut can).is_ok());
+        assert_eq!(
+            save(&mut slcan),
+            Settings {
+                serial_number: Some(*b"CAN1"),
+                ..saved
+            }
+        );
+
+        assert!(run(b"n", &mut slcan, &mut can).is_ok());
+        assert_eq!(save(&mut slcan), saved);
+    }
+
+    #[test]
+    fn restores_settings_at_startup() {
+        let settings = Settings {
+            bit_timing: Some(BitTimingSetup::Bitrate(CANBitrate::Bitrate500k)),
+            acceptance_code: 0x1234_5678,
+            acceptance_mask: 0x0000_FFFF,
+            timestamps_enabled: true,
+            auto_poll: false,
+            auto_startup: AutoStartup::Off,
+            serial_number: Some(*b"CAN1"),
+        };
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        slcan.apply_settings(&settings, &mut can).unwrap();
+        let expected = timing::calculate(CLOCK_HZ, 500_000).unwrap().bxcan();
+        assert_eq!(can.bit_timing, Some(expected));
+        assert!(!can.is_enabled());
+        assert_eq!(slcan.settings(AutoStartup::Off), settings);
+        assert_eq!(slcan.serial_number(), *b"CAN1");
+
+        let settings = Settings {
+            auto_startup: AutoStartup::ListenOnly,
+            ..settings
+        };
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        slcan.apply_settings(&settings, &mut can).unwrap();
+        assert!(can.is_enabled());
+        assert!(can.is_silent());
+        assert_eq!(can.acceptance_filter, Some((0x1234_5678, 0x0000_FFFF)));
+    }
+
+    #[test]
+    fn applies_acceptance_filter_on_open() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        assert!(run(b"M12345678", &mut slcan, &mut can).is_ok());
+        assert!(run(b"m0000FFFF", &mut slcan, &mut can).is_ok());
+        assert_eq!(can.acceptance_filter, None);
+
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        assert_eq!(can.acceptance_filter, Some((0x1234_5678, 0x0000_FFFF)));
+
+        // the filter cannot be changed while the channel is open
+        assert!(run(b"M00000000", &mut slcan, &mut can).is_err());
+        assert!(run(b"mFFFFFFFF", &mut slcan, &mut can).is_err());
+    }
+
+    #[test]
+    fn runs_listen_only_open() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        assert!(run(b"M12345678", &mut slcan, &mut can).is_ok());
+        assert!(run(b"L", &mut slcan, &mut can).is_ok());
+        assert!(can.is_enabled());
+        assert!(can.is_silent());
+        assert_eq!(can.acceptance_filter, Some((0x1234_5678, 0xFFFF_FFFF)));
+
+        // nothing may be sent while listening only
+        for bytes in [&b"t1230"[..], b"T123456780", b"r1230", b"R123456780"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_err());
+        }
+        assert!(can.transmitted().is_empty());
+
+        assert!(run(b"C", &mut slcan, &mut can).is_ok());
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        assert!(!can.is_silent());
+        assert!(run(b"t1230", &mut slcan, &mut can).is_ok());
+        assert_eq!(can.transmitted().len(), 1);
+    }
+
+    #[test]
+    fn loops_back_frames_in_test_modes() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut tx_queue = TxQueueType::new();
+
+        assert!(run(b"l2", &mut slcan, &mut can).is_ok());
+        assert_eq!(can.test_mode(), TestMode::SilentLoopback);
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        assert!(run(b"t1231AA", &mut slcan, &mut can).is_ok());
+        // the mode cannot be changed while the channel is open
+        assert!(run(b"l0", &mut slcan, &mut can).is_err());
+        assert!(run(b"C", &mut slcan, &mut can).is_ok());
+
+        assert!(run(b"l1", &mut slcan, &mut can).is_ok());
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        assert!(run(b"t4560", &mut slcan, &mut can).is_ok());
+
+        while let Ok(frame) = can.receive() {
+            slcan
+                .handle_incoming_can_frame(&frame, 0, &mut tx_queue)
+                .unwrap();
+        }
+        let output: heapless::Vec<u8, 32> = tx_queue.iter().copied().collect();
+        assert_eq!(output, b"t1231AA\rt4560\r"[..]);
+        // only the frame sent in loopback mode reached the bus
+        assert_eq!(
+            can.transmitted(),
+            [bxcan::Frame::new_data(standard_id(0x456), [])]
+        );
+
+        for bytes in [&b"l"[..], b"l3", b"l00"] {
+            assert!(run(b"C", &mut slcan, &mut can).is_ok());
+            assert!(run(bytes, &mut slcan, &mut can).is_err());
+        }
+    }
+
+    #[test]
+    fn runs_transmit_commands() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        for (bytes, ack) in [
+            (&b"t1232AABB"[..], b"z"),
+            (b"T123456780", b"Z"),
+            (b"r7FF8", b"z"),
+            (b"R1FFFFFFF0", b"Z"),
+        ] {
+            assert_eq!(run(bytes, &mut slcan, &mut can).unwrap(), ack[..]);
+        }
+        assert!(run(b"t1232AA", &mut slcan, &mut can).is_err());
+
+        assert_eq!(
+            can.transmitted(),
+            [
+                bxcan::Frame::new_data(standard_id(0x123), [0xAA, 0xBB]),
+                bxcan::Frame::new_data(extended_id(0x12345678), []),
+                bxcan::Frame::new_remote(standard_id(0x7FF), 8),
+                bxcan::Frame::new_remote(extended_id(0x1FFFFFFF), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn acknowledges_transmit_and_reports_displaced_frames() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        can.displaced_frame = Some(bxcan::Frame::new_data(standard_id(0x7FF), []));
+        assert_eq!(run(b"t1000", &mut slcan, &mut can).unwrap(), b"z"[..]);
+        assert_eq!(run(b"F", &mut slcan, &mut can).unwrap(), b"F02"[..]);
+
+        // without auto-poll, only the terminator is returned
+        slcan.auto_poll = false;
+        assert!(run(b"t1000", &mut slcan, &mut can).unwrap().is_empty());
+        assert!(run(b"T100000000", &mut slcan, &mut can).unwrap().is_empty());
+    }
+
+    #[test]
+    fn runs_auto_poll_toggle() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        assert!(run(b"X0", &mut slcan, &mut can).is_ok());
+        assert!(!slcan.auto_poll);
+        assert!(run(b"X1", &mut slcan, &mut can).is_ok());
+        assert!(slcan.auto_poll);
+        for bytes in [&b"X"[..], b"X2", b"X10"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_err());
+        }
+
+        // polling needs an open channel with auto-poll off
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        assert!(run(b"X0", &mut slcan, &mut can).is_err());
+        assert!(run(b"P", &mut slcan, &mut can).is_err());
+        assert!(run(b"A", &mut slcan, &mut can).is_err());
+        assert!(run(b"C", &mut slcan, &mut can).is_ok());
+        assert!(run(b"X0", &mut slcan, &mut can).is_ok());
+        assert!(run(b"P", &mut slcan, &mut can).is_err());
+        assert!(run(b"A", &mut slcan, &mut can).is_err());
+    }
+
+    #[test]
+    fn holds_received_frames_until_polled() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut tx_queue = TxQueueType::new();
+
+        assert!(run(b"X0", &mut slcan, &mut can).is_ok());
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        for id in 0x100..0x104 {
+            let frame = bxcan::Frame::new_data(standard_id(id), [0xAA]);
+            slcan
+                .handle_incoming_can_frame(&frame, 0, &mut tx_queue)
+                .unwrap();
+        }
+        assert!(tx_queue.is_empty());
+
+        assert_eq!(run(b"P", &mut slcan, &mut can).unwrap(), b"t1001AA"[..]);
+        assert_eq!(run(b"P", &mut slcan, &mut can).unwrap(), b"t1011AA"[..]);
+
+        let output = run(b"A", &mut slcan, &mut can);
+        slcan.handle_command_output(&output, &mut tx_queue).unwrap();
+        let output: heapless::Vec<u8, 32> = tx_queue.iter().copied().collect();
+        assert_eq!(output, b"t1021AA\rt1031AA\rA\r"[..]);
+
+        // with nothing left, P returns only the terminator and A only its own line
+        assert!(run(b"P", &mut slcan, &mut can).unwrap().is_empty());
+        assert_eq!(run(b"A", &mut slcan, &mut can).unwrap(), b"A"[..]);
+    }
+
+    #[test]
+    fn discards_unpolled_frames_on_close() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut tx_queue = TxQueueType::new();
+
+        assert!(run(b"X0", &mut slcan, &mut can).is_ok());
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        let frame = bxcan::Frame::new_data(standard_id(0x123), []);
+        slcan
+            .handle_incoming_can_frame(&frame, 0, &mut tx_queue)
+            .unwrap();
+
+        for bytes in [&b"C"[..], b"S3", b"O"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_ok());
+        }
+        assert!(run(b"P", &mut slcan, &mut can).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_full_receive_fifo() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut tx_queue = TxQueueType::new();
+
+        assert!(run(b"X0", &mut slcan, &mut can).is_ok());
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        let frame = bxcan::Frame::new_data(standard_id(0x123), []);
+        for _ in 0..RECEIVE_FIFO_LEN {
+            slcan
+                .handle_incoming_can_frame(&frame, 0, &mut tx_queue)
+                .unwrap();
+        }
+        assert!(slcan
+            .handle_incoming_can_frame(&frame, 0, &mut tx_queue)
+            .is_err());
+        assert_eq!(run(b"F", &mut slcan, &mut can).unwrap(), b"F01"[..]);
+    }
+
+    #[test]
+    fn keeps_polled_frames_which_do_not_fit() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut tx_queue = TxQueueType::new();
+
+        assert!(run(b"X0", &mut slcan, &mut can).is_ok());
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        let frame = bxcan::Frame::new_data(standard_id(0x123), []);
+        for _ in 0..2 {
+            slcan
+                .handle_incoming_can_frame(&frame, 0, &mut tx_queue)
+                .unwrap();
+        }
+
+        // room for one frame (6 bytes) and the A response (2 bytes)
+        while tx_queue.capacity() - tx_queue.len() > 8 {
+            tx_queue.push_back(b'x').unwrap();
+        }
+        // the error in place of A tells the host that frames are left
+        let output = run(b"A", &mut slcan, &mut can);
+        assert!(matches!(
+            slcan.handle_command_output(&output, &mut tx_queue),
+            Err(SLCANError::Regular(ErrorKind::QueueFull))
+        ));
+        let output: heapless::Vec<u8, 8> = tx_queue
+            .iter()
+            .copied()
+            .skip_while(|&b| b == b'x')
+            .collect();
+        assert_eq!(output, b"t1230\r\x07"[..]);
+
+        tx_queue.clear();
+        let output = run(b"A", &mut slcan, &mut can);
+        slcan.handle_command_output(&output, &mut tx_queue).unwrap();
+        let output: heapless::Vec<u8, 8> = tx_queue.iter().copied().collect();
+        assert_eq!(output, b"t1230\rA\r"[..]);
+    }
+
+    #[test]
+    fn rejects_transmit_when_queue_full() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        can.queue_full = true;
+        assert!(matches!(
+            run(b"t1000", &mut slcan, &mut can),
+            Err(SLCANError::Regular(ErrorKind::QueueFull))
+        ));
+        assert!(can.transmitted().is_empty());
+        assert_eq!(run(b"F", &mut slcan, &mut can).unwrap(), b"F02"[..]);
+
+        can.queue_full = false;
+        assert!(run(b"t1000", &mut slcan, &mut can).is_ok());
+        assert_eq!(run(b"F", &mut slcan, &mut can).unwrap(), b"F00"[..]);
+    }
+
+    #[test]
+    fn reports_and_clears_status_flags() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+
+        can.bus_status.error_passive = true;
+        can.bus_status.bus_error = true;
+        assert_eq!(run(b"F", &mut slcan, &mut can).unwrap(), b"FA0"[..]);
+        assert_eq!(run(b"F", &mut slcan, &mut can).unwrap(), b"F00"[..]);
+    }
+
+    #[test]
+    fn reports_error_state() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        assert_eq!(run(b"E", &mut slcan, &mut can).unwrap(), b"E000000"[..]);
+
+        can.error_state = ErrorState {
+            transmit_error_count: 0x80,
+            receive_error_count: 0x0A,
+            last_error: LastErrorCode::Acknowledgement,
+        };
+        assert_eq!(run(b"E", &mut slcan, &mut can).unwrap(), b"E800A03"[..]);
+    }
+
+    #[test]
+    fn runs_bus_off_recovery_selection() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        assert_eq!(can.bus_off_recovery(), BusOffRecovery::Automatic);
+
+        assert!(run(b"b1", &mut slcan, &mut can).is_ok());
+        assert_eq!(can.bus_off_recovery(), BusOffRecovery::Manual);
+        assert!(run(b"b203E8", &mut slcan, &mut can).is_ok());
+        assert_eq!(can.bus_off_recovery(), BusOffRecovery::Delayed(1000));
+        assert!(run(b"b0", &mut slcan, &mut can).is_ok());
+        assert_eq!(can.bus_off_recovery(), BusOffRecovery::Automatic);
+
+        for bytes in [&b"b"[..], b"b3", b"b2", b"b203E", b"b2XXXX", b"b00"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_err());
+        }
+
+        // the policy cannot be changed while the channel is open
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        assert!(run(b"b1", &mut slcan, &mut can).is_err());
+    }
+
+    #[test]
+    fn notifies_bus_off_transitions() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut tx_queue = TxQueueType::new();
+
+        // nothing is reported while the channel is closed
+        can.bus_off = true;
+        slcan.update_bus_state(&mut can, 0, &mut tx_queue);
+        assert!(tx_queue.is_empty());
+
+        assert!(run(b"b1", &mut slcan, &mut can).is_ok());
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        can.bus_off = true;
+        slcan.update_bus_state(&mut can, 0, &mut tx_queue);
+        slcan.update_bus_state(&mut can, 5_000_000, &mut tx_queue);
+        assert!(can.is_bus_off());
+        assert_eq!(run(b"F", &mut slcan, &mut can).unwrap(), b"F10"[..]);
+
+        // manual recovery by opening the channel again
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        slcan.update_bus_state(&mut can, 5_050_000, &mut tx_queue);
+
+        let output: heapless::Vec<u8, 32> = tx_queue.iter().copied().collect();
+        assert_eq!(output, b"!bus-off\r!bus-on\r"[..]);
+    }
+
+    #[test]
+    fn notifies_bus_off_recovered_between_updates() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut tx_queue = TxQueueType::new();
+
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        slcan.update_bus_state(&mut can, 0, &mut tx_queue);
+        // automatic recovery completed before the next update
+        can.went_bus_off = true;
+        slcan.update_bus_state(&mut can, 50_000, &mut tx_queue);
+        slcan.update_bus_state(&mut can, 100_000, &mut tx_queue);
+        assert_eq!(run(b"F", &mut slcan, &mut can).unwrap(), b"F10"[..]);
+
+        let output: heapless::Vec<u8, 32> = tx_queue.iter().copied().collect();
+        assert_eq!(output, b"!bus-off\r!bus-on\r"[..]);
+    }
+
+    #[test]
+    fn recovers_from_bus_off_after_delay() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut tx_queue = TxQueueType::new();
+
+        assert!(run(b"b20064", &mut slcan, &mut can).is_ok());
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        can.bus_off = true;
+        // the delay is measured across a timer wrap
+        slcan.update_bus_state(&mut can, u32::MAX - 50_000, &mut tx_queue);
+        slcan.update_bus_state(&mut can, 40_000, &mut tx_queue);
+        assert!(can.is_bus_off());
+
+        slcan.update_bus_state(&mut can, 50_000, &mut tx_queue);
+        assert!(!can.is_bus_off());
+        slcan.update_bus_state(&mut can, 100_000, &mut tx_queue);
+
+        let output: heapless::Vec<u8, 32> = tx_queue.iter().copied().collect();
+        assert_eq!(output, b"!bus-off\r!bus-on\r"[..]);
+    }
+
+    #[test]
+    fn decodes_standard_rtr_frame() {
+        let frame = command(b"r1232").decode_standard_frame(true).unwrap();
+        assert_eq!(frame, bxcan::Frame::new_remote(standard_id(0x123), 2));
+
+        let frame = command(b"r7FF0").decode_standard_frame(true).unwrap();
+        assert_eq!(frame, bxcan::Frame::new_remote(standard_id(0x7FF), 0));
+    }
+
+    #[test]
+    fn decodes_extended_rtr_frame() {
+        let frame = command(b"R123456788").decode_extended_frame(true).unwrap();
+        assert_eq!(frame, bxcan::Frame::new_remote(extended_id(0x12345678), 8));
+    }
+
+    #[test]
+    fn rejects_malformed_rtr_frames() {
+        for bytes in [
+            &b"r"[..],
+            b"r123",
+            b"r1239",
+            b"r123A",
+            b"r1232AABB",
+            b"r8000",
+            b"rXYZ1",
+            b"R12345678",
+            b"R123456789",
+            b"R1234567811",
+            b"R200000000",
+            b"R1234567G0",
+        ] {
+            assert!(command(bytes).decode_frame().is_err(), "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn decodes_data_frames() {
+        let frame = command(b"t12321122").decode_frame().unwrap();
+        assert_eq!(
+            frame,
+            bxcan::Frame::new_data(standard_id(0x123), [0x11, 0x22])
+        );
+
+        let frame = command(b"T123456780").decode_frame().unwrap();
+        assert_eq!(frame, bxcan::Frame::new_data(extended_id(0x12345678), []));
+    }
+
+    #[test]
+    fn decodes_acceptance_registers() {
+        assert_eq!(
+            command(b"M12345678").decode_acceptance_register().unwrap(),
+            0x1234_5678
+        );
+        assert_eq!(
+            command(b"mFFFFFFFF").decode_acceptance_register().unwrap(),
+            0xFFFF_FFFF
+        );
+        for bytes in [&b"M"[..], b"M1234567", b"M123456789", b"m1234567G"] {
+            assert!(command(bytes).decode_acceptance_register().is_err());
+        }
+    }
+
+    #[test]
+    fn represents_data_frames() {
+        let frame = bxcan::Frame::new_data(standard_id(0x7FF), [0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(
+            &SLCAN::can_frame_representation(&frame, true, None)[..],
+            b"t7FF4DEADBEEF"
+        );
+        assert_eq!(
+            &SLCAN::can_frame_representation(&frame, false, None)[..],
+            b"7FF4DEADBEEF"
+        );
+
+        let frame = bxcan::Frame::new_data(extended_id(0x1FFFFFFF), [0x01; 8]);
+        assert_eq!(
+            &SLCAN::can_frame_representation(&frame, true, None)[..],
+            b"T1FFFFFFF80101010101010101"
+        );
+
+        let frame = bxcan::Frame::new_data(standard_id(0x001), []);
+        assert_eq!(
+            &SLCAN::can_frame_representation(&frame, true, None)[..],
+            b"t0010"
+        );
+    }
+
+    #[test]
+    fn represents_remote_frames() {
+        let frame = bxcan::Frame::new_remote(standard_id(0x123), 2);
+        assert_eq!(
+            &SLCAN::can_frame_representation(&frame, true, None)[..],
+            b"r1232"
+        );
+
+        let frame = bxcan::Frame::new_remote(extended_id(0x12345678), 8);
+        assert_eq!(
+            &SLCAN::can_frame_representation(&frame, true, None)[..],
+            b"R123456788"
+        );
+    }
+
+    #[test]
+    fn represents_frames_with_timestamps() {
+        let mut timestamp = Timestamp::new();
+        timestamp.update(1_234_567);
+
+        let frame = bxcan::Frame::new_data(standard_id(0x123), [0x11, 0x22]);
+        assert_eq!(
+            &SLCAN::can_frame_representation(&frame, true, Some(&timestamp))[..],
+            b"t1232112204D2"
+        );
+    }
+
+    #[test]
+    fn timestamp_wraps_at_60000_ms() {
+        let mut timestamp = Timestamp::new();
+        assert_eq!(timestamp.update(59_999_999), 59999);
+        assert_eq!(timestamp.update(60_000_000), 0);
+        assert_eq!(timestamp.update(61_000_500), 1000);
+    }
+
+    #[test]
+    fn timestamp_survives_timer_wrap() {
+        let mut timestamp = Timestamp::new();
+        // 2^32 us = 4294967.296 ms, which is 34967 ms past a 60000 ms wrap
+        timestamp.update(u32::MAX - 999);
+        assert_eq!(timestamp.update(u32::MAX), 34967);
+        assert_eq!(timestamp.update(1_703), 34968);
+    }
+
+    #[test]
+    fn status_flags_use_lawicel_bit_order() {
+        let mut status = StatusFlags::new();
+        status.receive_queue_full = true;
+        assert_eq!(&status.as_hex(), b"01");
+
+        let mut status = StatusFlags::new();
+        status.update(&BusStatus {
+            error_warning: true,
+            error_passive: true,
+            bus_error: true,
+            ..Default::default()
+        });
+        assert_eq!(&status.as_hex(), b"A4");
+
+        let mut status = StatusFlags::new();
+        status.update(&BusStatus {
+            bus_off: true,
+            ..Default::default()
+        });
+        assert_eq!(&status.as_hex(), b"10");
+
+        let mut status = StatusFlags::new();
+        status.update(&BusStatus {
+            transmit_dropped: true,
+            ..Default::default()
+        });
+        assert_eq!(&status.as_hex(), b"02");
+    }
+
+    #[test]
+    fn command_output_is_queued_whole() {
+        let mut slcan = SLCAN::new();
+        let mut tx_queue = TxQueueType::new();
+        for _ in 0..tx_queue.capacity() - 3 {
+            tx_queue.push_back(b'x').unwrap();
+        }
+
+        let output = Ok(ResponseData::from_slice(b"V0101").unwrap());
+        assert!(slcan.handle_command_output(&output, &mut tx_queue).is_err());
+        assert_eq!(tx_queue.len(), tx_queue.capacity() - 3);
+        assert!(slcan.status.transmit_queue_full);
+
+        let output = Ok(ResponseData::from_slice(b"z").unwrap());
+        slcan.handle_command_output(&output, &mut tx_queue).unwrap();
+        assert!(tx_queue.iter().rev().take(2).eq(b"\rz".iter()));
+    }
+
+    #[test]
+    fn receives_commands_from_transport() {
+        let mut slcan = SLCAN::new();
+        let mut rx_queue = QueueType::new();
+        let mut transport = MemoryTransport::new();
+
+        transport.send(b"S6\rt1");
+        let cmd = slcan.receive_command(&mut transport, &mut rx_queue);
+        assert!(matches!(
+            cmd,
+            Some(Ok(Command {
+                variant: CommandVariant::Setup,
+                ..
+            }))
+        ));
+        // the partial command stays buffered until its terminator arrives
+        assert!(slcan
+            .receive_command(&mut transport, &mut rx_queue)
+            .is_none());
+
+        transport.send(b"230\rx\r");
+        let cmd = slcan.receive_command(&mut transport, &mut rx_queue);
+        let cmd = cmd.unwrap().unwrap();
+        assert!(matches!(cmd.variant, CommandVariant::TransmitFrame));
+        assert_eq!(cmd.data, b"1230"[..]);
+
+        let cmd = slcan.receive_command(&mut transport, &mut rx_queue);
+        assert!(matches!(
+            cmd,
+            Some(Err(SLCANError::Regular(ErrorKind::InvalidCommand)))
+        ));
+        assert!(slcan
+            .receive_command(&mut transport, &mut rx_queue)
+            .is_none());
+    }
+
+    #[test]
+    fn transmits_output_to_transport() {
+        let mut slcan = SLCAN::new();
+        let mut tx_queue = TxQueueType::new();
+        let mut transport = MemoryTransport::new();
+
+        let frame = bxcan::Frame::new_data(standard_id(0x123), [0x11, 0x22]);
+        slcan
+            .handle_incoming_can_frame(&frame, 0, &mut tx_queue)
+            .unwrap();
+        let response = ResponseData::from_slice(b"N0042").unwrap();
+        slcan
+            .handle_command_output(&Ok(response), &mut tx_queue)
+            .unwrap();
+        slcan
+            .handle_command_output(
+                &Err(SLCANError::Regular(ErrorKind::CANError)),
+                &mut tx_queue,
+            )
+            .unwrap();
+
+        // the rest is left queued while the transmitter is busy
+        transport.set_write_space(4);
+        assert!(slcan.transmit_output(&mut transport, &mut tx_queue));
+        assert!(transport.tx_pending);
+        assert_eq!(transport.received(), b"t123");
+
+        transport.set_write_space(usize::MAX);
+        assert!(!slcan.transmit_output(&mut transport, &mut tx_queue));
+        assert!(!transport.tx_pending);
+        assert!(tx_queue.is_empty());
+        assert_eq!(transport.received(), b"t12321122\rN0042\r\x07");
+    }
+
+    #[test]
+    fn switches_baud_rate_once_response_is_sent() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut tx_queue = TxQueueType::new();
+        let mut transport = MemoryTransport::new();
+
+        for bytes in [&b"U"[..], b"UC", b"Ux", b"U10"] {
+            assert!(run(bytes, &mut slcan, &mut can).is_err());
+        }
+        assert!(run(b"O", &mut slcan, &mut can).is_ok());
+        assert!(run(b"U9", &mut slcan, &mut can).is_err());
+        assert!(run(b"C", &mut slcan, &mut can).is_ok());
+
+        let output = run(b"U9", &mut slcan, &mut can);
+        slcan.handle_command_output(&output, &mut tx_queue).unwrap();
+        transport.set_write_space(0);
+        assert!(slcan.transmit_output(&mut transport, &mut tx_queue));
+        assert_eq!(transport.baud_rate, None);
+
+        transport.set_write_space(usize::MAX);
+        assert!(!slcan.transmit_output(&mut transport, &mut tx_queue));
+        assert_eq!(transport.received(), b"\r");
+        assert_eq!(transport.baud_rate, Some(1_000_000));
+
+        assert!(run(b"U1", &mut slcan, &mut can).is_ok());
+        slcan.transmit_output(&mut transport, &mut tx_queue);
+        assert_eq!(transport.baud_rate, Some(DEFAULT_UART_BAUD_RATE));
+    }
+
+    #[test]
+    fn serves_commands_and_frames_over_transport() {
+        let mut slcan = SLCAN::new();
+        let mut can = MockCanDriver::new(CLOCK_HZ);
+        let mut rx_queue = QueueType::new();
+        let mut tx_queue = TxQueueType::new();
+        let mut transport = MemoryTransport::new();
+
+        transport.send(b"S6\rZ1\rO\rt1231AA\rX\r");
+        while let Some(cmd) = slcan.receive_command(&mut transport, &mut rx_queue) {
+            let output = cmd.and_then(|cmd| cmd.run(&mut slcan, &mut can));
+            slcan.handle_command_output(&output, &mut tx_queue).unwrap();
+        }
+
+        can.inject(bxcan::Frame::new_data(extended_id(0x100), [0x42]));
+        while let Ok(frame) = can.receive() {
+            slcan
+                .handle_incoming_can_frame(&frame, 1_500, &mut tx_queue)
+                .unwrap();
+        }
+
+        assert!(!slcan.transmit_output(&mut transport, &mut tx_queue));
+        assert_eq!(transport.received(), b"\r\r\rz\r\x07T000001001420001\r");
+        assert_eq!(
+            can.transmitted(),
+            [bxcan::Frame::new_data(standard_id(0x123), [0xAA])]
+        );
     }
 }
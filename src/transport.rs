@@ -0,0 +1,89 @@
+/// Byte stream connection to the host, over which SLCAN commands and responses are exchanged.
+pub trait Transport {
+    /// Services the underlying device. Called before reading or writing.
+    fn poll(&mut self) {}
+
+    /// Reads the next byte received from the host, if any.
+    fn read(&mut self) -> Option<u8>;
+
+    /// Writes as much of `data` as the transport accepts, returning the number of bytes written.
+    fn write(&mut self, data: &[u8]) -> usize;
+
+    /// Tells the transport whether bytes are left to send after writing,
+    /// so that it can interrupt again once it accepts more.
+    fn set_tx_pending(&mut self, _pending: bool) {}
+
+    /// Switches the link to `baud` bits per second, once everything written has been sent.
+    /// Links without a baud rate, e.g. USB, ignore it.
+    fn set_baud_rate(&mut self, _baud: u32) {}
+}
+
+/// In-memory transport for exercising the command/response path on the host.
+pub struct MemoryTransport {
+    input: heapless::Deque<u8, 256>,
+    output: heapless::Vec<u8, 1024>,
+    write_space: usize,
+    pub tx_pending: bool,
+    /// Baud rate last set through the transport.
+    pub baud_rate: Option<u32>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        MemoryTransport {
+            input: heapless::Deque::new(),
+            output: heapless::Vec::new(),
+            write_space: usize::MAX,
+            tx_pending: false,
+            baud_rate: None,
+        }
+    }
+
+    /// Limits the number of bytes accepted by further writes, as if the transmitter
+    /// were busy once they have been written.
+    pub fn set_write_space(&mut self, write_space: usize) {
+        self.write_space = write_space;
+    }
+
+    /// Queues bytes as if sent by the host.
+    pub fn send(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.input.push_back(byte).unwrap();
+        }
+    }
+
+    /// Bytes written to the host so far.
+    pub fn received(&self) -> &[u8] {
+        &self.output
+    }
+}
+
+impl Default for MemoryTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn write(&mut self, data: &[u8]) -> usize {
+        let count = data
+            .len()
+            .min(self.write_space)
+            .min(self.output.capacity() - self.output.len());
+        self.output.extend_from_slice(&data[..count]).unwrap();
+        self.write_space -= count;
+        count
+    }
+
+    fn set_tx_pending(&mut self, pending: bool) {
+        self.tx_pending = pending;
+    }
+
+    fn set_baud_rate(&mut self, baud: u32) {
+        self.baud_rate = Some(baud);
+    }
+}
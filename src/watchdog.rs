@@ -0,0 +1,75 @@
+/// Tracks check-ins from the supervised periodic tasks, so that the watchdog is only fed
+/// while every one of them keeps running.
+pub struct TaskMonitor {
+    expected: u32,
+    checked_in: u32,
+}
+
+impl TaskMonitor {
+    /// Supervises tasks numbered `0..task_count`.
+    pub const fn new(task_count: u32) -> Self {
+        assert!(task_count <= u32::BITS);
+        TaskMonitor {
+            expected: ((1u64 << task_count) - 1) as u32,
+            checked_in: 0,
+        }
+    }
+
+    /// Records that `task` has run.
+    pub fn check_in(&mut self, task: u32) {
+        self.checked_in |= 1u32.checked_shl(task).unwrap_or(0) & self.expected;
+    }
+
+    /// Returns whether every task has checked in since the last call,
+    /// starting a new round of check-ins if so.
+    pub fn all_checked_in(&mut self) -> bool {
+        if self.checked_in != self.expected {
+            return false;
+        }
+        self.checked_in = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_every_task() {
+        let mut monitor = TaskMonitor::new(3);
+        assert!(!monitor.all_checked_in());
+
+        monitor.check_in(0);
+        monitor.check_in(2);
+        assert!(!monitor.all_checked_in());
+
+        monitor.check_in(1);
+        assert!(monitor.all_checked_in());
+        // each round needs fresh check-ins
+        assert!(!monitor.all_checked_in());
+    }
+
+    #[test]
+    fn keeps_check_ins_until_complete() {
+        let mut monitor = TaskMonitor::new(2);
+        monitor.check_in(0);
+        assert!(!monitor.all_checked_in());
+        monitor.check_in(1);
+        assert!(monitor.all_checked_in());
+    }
+
+    #[test]
+    fn ignores_unknown_tasks() {
+        let mut monitor = TaskMonitor::new(1);
+        monitor.check_in(5);
+        monitor.check_in(40);
+        assert!(!monitor.all_checked_in());
+
+        let mut monitor = TaskMonitor::new(32);
+        for task in 0..32 {
+            monitor.check_in(task);
+        }
+        assert!(monitor.all_checked_in());
+    }
+}